@@ -0,0 +1,418 @@
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use memmap2::MmapMut;
+use rand::{rngs::SmallRng, seq::index, SeedableRng};
+use ssd_vectune::{
+    graph::{GraphMetadata, UnorderedGraph},
+    graph_store::GraphStore,
+    point::Point,
+    storage::StorageTrait,
+};
+use vectune::{GraphInterface, PointInterface};
+
+use crate::groundtruth::{squared_euclidean, Fbin};
+use crate::point::CosinePoint;
+use crate::{ChunkReader, GraphLayout, Metric};
+
+/// Knobs of `tool build`.
+pub struct BuildParams {
+    pub edge_degrees: usize,
+    /// Pruning factor of the shard builds. Merging the shards prunes with the one of `UnorderedGraph`.
+    pub alpha: f32,
+    pub size_l: usize,
+    /// With more than one shard, every vector is indexed in the shards of its two closest
+    /// centroids and the shard graphs are merged, which keeps every single build small. Only the
+    /// vectors of the shard being built are held in memory, the others stay in the mapped file.
+    pub num_shards: usize,
+    /// Number of recently placed nodes gorder keeps a new node close to.
+    pub gorder_window: usize,
+    pub sector_byte_size: usize,
+    pub seed: u64,
+    /// The distance the graph is built with. Recorded in the header, which `start` compares with
+    /// the metric `initialize` was given.
+    pub metric: Metric,
+    /// The chunks `tool upload` splits the graph into, which the index digest of the header hashes.
+    pub chunk_byte_size: usize,
+}
+
+/// Builds the Vamana graph of an `.fbin` file and writes it in the layout `GraphStore` reads,
+/// along with the `GraphMetadata` file and the graph header `tool upload` expects.
+pub fn build(source_path: &str, graph_path: &str, graph_metadata_path: &str, header_path: &str, params: &BuildParams) -> Result<()> {
+    let source = Fbin::open(source_path)?;
+    let (num_vectors, vector_dim) = (source.num_vectors, source.vector_dim);
+    anyhow::ensure!(num_vectors > 0, "{source_path} has no vectors");
+    anyhow::ensure!(num_vectors <= u32::MAX as usize, "{source_path} has more vectors than node indices");
+
+    println!("finding the medoid of {num_vectors} vectors of {vector_dim} dimensions..");
+    let medoid = medoid((0..num_vectors).map(|index| source.vector(index)), vector_dim) as u32;
+
+    let edges = match params.metric {
+        Metric::Euclidean => build_edges::<Point>(&source, medoid, graph_path, params)?,
+        Metric::Cosine => {
+            CosinePoint::set_dim(vector_dim as u32);
+            build_edges::<CosinePoint>(&source, medoid, graph_path, params)?
+        }
+        // Pruning needs a distance that obeys the triangle inequality, which the negated inner
+        // product does not.
+        Metric::InnerProduct => anyhow::bail!("build does not support {:?}", params.metric),
+    };
+
+    println!("reordering the nodes with gorder..");
+    let order = gorder(&edges, medoid, params.gorder_window);
+    let mut new_indices = vec![0; num_vectors];
+    for (new_index, &old_index) in order.iter().enumerate() {
+        new_indices[old_index as usize] = new_index as u32;
+    }
+
+    println!("writing {graph_path}..");
+    let graph_store = create_graph_store(graph_path, &source, params)?;
+    for (new_index, &old_index) in order.iter().enumerate() {
+        let out_edges: Vec<u32> = edges[old_index as usize].iter().map(|&edge| new_indices[edge as usize]).collect();
+        graph_store.write_node(&(new_index as u32), &source.vector(old_index as usize).to_vec(), &out_edges);
+    }
+    drop(graph_store);
+
+    let layout = GraphLayout {
+        medoid_node_index: new_indices[medoid as usize],
+        sector_byte_size: params.sector_byte_size as u64,
+        num_vectors: num_vectors as u64,
+        vector_dim: vector_dim as u64,
+        edge_degrees: params.edge_degrees as u64,
+    };
+    GraphMetadata::new(
+        layout.medoid_node_index,
+        params.sector_byte_size,
+        num_vectors,
+        vector_dim,
+        params.edge_degrees,
+    )
+    .save(graph_metadata_path)?;
+
+    println!("writing {header_path}..");
+    let chunk_hashes = ChunkReader::new(graph_path, params.chunk_byte_size)?.chunk_hashes();
+    let header = crate::graph_header(&layout, params.metric, &crate::index_digest(&chunk_hashes));
+    std::fs::write(header_path, header)?;
+
+    Ok(())
+}
+
+/// A graph file of every vector of `source`, sized for the layout of `params`.
+fn create_graph_store(path: &str, source: &Fbin, params: &BuildParams) -> Result<GraphStore<FileStorage>> {
+    let file_byte_size = crate::graph_byte_size(
+        source.num_vectors as u64,
+        source.vector_dim as u64,
+        params.edge_degrees as u64,
+        params.sector_byte_size as u64,
+    )?;
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+    file.set_len(file_byte_size)?;
+    let storage = FileStorage::new(unsafe { MmapMut::map_mut(&file)? }, params.sector_byte_size);
+    Ok(GraphStore::new(source.num_vectors, source.vector_dim, params.edge_degrees, storage))
+}
+
+pub(crate) struct FileStorage {
+    mmap: Mutex<MmapMut>,
+    sector_byte_size: usize,
+}
+
+impl FileStorage {
+    pub(crate) fn new(mmap: MmapMut, sector_byte_size: usize) -> Self {
+        Self { mmap: Mutex::new(mmap), sector_byte_size }
+    }
+}
+
+impl StorageTrait for FileStorage {
+    fn read(&self, offset: u64, dst: &mut [u8]) {
+        let offset = offset as usize;
+        dst.copy_from_slice(&self.mmap.lock().unwrap()[offset..offset + dst.len()]);
+    }
+
+    fn write(&self, offset: u64, src: &[u8]) {
+        let offset = offset as usize;
+        self.mmap.lock().unwrap()[offset..offset + src.len()].copy_from_slice(src);
+    }
+
+    fn sector_byte_size(&self) -> usize {
+        self.sector_byte_size
+    }
+}
+
+/// The out-edges of every node, in the order of `source`.
+fn build_edges<P>(source: &Fbin, medoid: u32, graph_path: &str, params: &BuildParams) -> Result<Vec<Vec<u32>>>
+where
+    P: PointInterface,
+    UnorderedGraph<FileStorage>: GraphInterface<P>,
+{
+    if params.num_shards <= 1 {
+        println!("building the graph..");
+        return Ok(build_shard(load_points::<P>(source, 0..source.num_vectors as u32), params));
+    }
+
+    let scratch_path = format!("{graph_path}.unordered");
+    let edges = merge_index::<P>(source, medoid, &scratch_path, params)?;
+    std::fs::remove_file(&scratch_path)?;
+    Ok(edges)
+}
+
+/// The vectors of `node_indices`, copied out of the mapped file for `vectune::Builder`.
+fn load_points<P: PointInterface>(source: &Fbin, node_indices: impl IntoIterator<Item = u32>) -> Vec<P> {
+    node_indices.into_iter().map(|node_index| load_point(source, node_index)).collect()
+}
+
+fn load_point<P: PointInterface>(source: &Fbin, node_index: u32) -> P {
+    P::from_f32_vec(source.vector(node_index as usize).to_vec())
+}
+
+fn build_shard<P: PointInterface>(points: Vec<P>, params: &BuildParams) -> Vec<Vec<u32>> {
+    let (nodes, _centroid) = vectune::Builder::default()
+        .set_r(params.edge_degrees)
+        .set_a(params.alpha)
+        .set_l(params.size_l)
+        .set_seed(params.seed)
+        .build(points);
+
+    nodes.into_iter().map(|(_point, out_edges)| out_edges).collect()
+}
+
+/// Builds one graph per shard, then unions the out-edges every node got in its shards and prunes
+/// them back to `edge_degrees` with `vectune::prune`. The pruning reads the vectors through an
+/// `UnorderedGraph`, which is written to `scratch_path` for it.
+fn merge_index<P>(source: &Fbin, medoid: u32, scratch_path: &str, params: &BuildParams) -> Result<Vec<Vec<u32>>>
+where
+    P: PointInterface,
+    UnorderedGraph<FileStorage>: GraphInterface<P>,
+{
+    let mut rng = SmallRng::seed_from_u64(params.seed);
+    let num_shards = std::cmp::min(params.num_shards, source.num_vectors);
+    let centroids: Vec<P> = index::sample(&mut rng, source.num_vectors, num_shards)
+        .into_iter()
+        .map(|index| load_point(source, index as u32))
+        .collect();
+
+    let mut shards: Vec<Vec<u32>> = vec![vec![]; num_shards];
+    for node_index in 0..source.num_vectors as u32 {
+        let point: P = load_point(source, node_index);
+        let mut closest: Vec<(f32, usize)> = centroids
+            .iter()
+            .enumerate()
+            .map(|(shard_index, centroid)| (point.distance(centroid), shard_index))
+            .collect();
+        closest.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for &(_, shard_index) in closest.iter().take(2) {
+            shards[shard_index].push(node_index);
+        }
+    }
+
+    let mut candidates: Vec<Vec<u32>> = vec![vec![]; source.num_vectors];
+    for (shard_index, shard) in shards.iter().enumerate() {
+        println!("building shard {}/{num_shards} of {} vectors..", shard_index + 1, shard.len());
+        if shard.is_empty() {
+            continue;
+        }
+        for (local_index, out_edges) in build_shard(load_points::<P>(source, shard.iter().copied()), params).into_iter().enumerate() {
+            let node_index = shard[local_index];
+            candidates[node_index as usize].extend(out_edges.into_iter().map(|edge| shard[edge as usize]));
+        }
+    }
+
+    println!("merging the shards..");
+    let graph_store = create_graph_store(scratch_path, source, params)?;
+    for node_index in 0..source.num_vectors as u32 {
+        graph_store.write_node(&node_index, &source.vector(node_index as usize).to_vec(), &vec![]);
+    }
+    let mut graph = UnorderedGraph::new(graph_store, medoid);
+
+    let edges = candidates
+        .into_iter()
+        .enumerate()
+        .map(|(node_index, out_edges)| {
+            let node_index = node_index as u32;
+            let point: P = load_point(source, node_index);
+            let mut candidates: Vec<(f32, u32)> = out_edges
+                .into_iter()
+                .filter(|&candidate| candidate != node_index)
+                .map(|candidate| (point.distance(&load_point::<P>(source, candidate)), candidate))
+                .collect();
+            candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+            candidates.dedup_by_key(|(_, candidate)| *candidate);
+            vectune::prune::<P, _>(&mut graph, &mut candidates)
+        })
+        .collect();
+    Ok(edges)
+}
+
+/// The index of the vector closest to the mean of `vectors`, where searches start. Takes two
+/// passes over `vectors`, so that they can be read from a file instead of memory.
+pub(crate) fn medoid<'a>(vectors: impl Iterator<Item = &'a [f32]> + Clone, vector_dim: usize) -> usize {
+    // Summed in f64, which keeps its precision over many vectors.
+    let mut sums = vec![0.0f64; vector_dim];
+    let mut num_vectors = 0;
+    for vector in vectors.clone() {
+        for (sum, &value) in sums.iter_mut().zip(vector) {
+            *sum += value as f64;
+        }
+        num_vectors += 1;
+    }
+    let mean: Vec<f32> = sums.into_iter().map(|sum| (sum / num_vectors as f64) as f32).collect();
+
+    vectors
+        .enumerate()
+        .map(|(index, vector)| (squared_euclidean(vector, &mean), index))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap()
+        .1
+}
+
+/// Orders the nodes so that nodes sharing neighbors end up next to each other, and so in the same
+/// sectors, following Gorder (Wei et al., SIGMOD 2016). Returns the old index of every new index.
+///
+/// A node scores one point for every edge it has with a node of the last `window` placed ones,
+/// and one for every in-neighbor it shares with them. The highest scoring node is placed next,
+/// of equal ones the one which reached that score last.
+fn gorder(edges: &[Vec<u32>], start: u32, window: usize) -> Vec<u32> {
+    let num_nodes = edges.len();
+    let mut in_edges: Vec<Vec<u32>> = vec![vec![]; num_nodes];
+    for (node_index, out_edges) in edges.iter().enumerate() {
+        for &edge in out_edges {
+            in_edges[edge as usize].push(node_index as u32);
+        }
+    }
+
+    let mut placed = vec![false; num_nodes];
+    let mut heap = UnitHeap::new(num_nodes);
+    let mut order: Vec<u32> = Vec::with_capacity(num_nodes);
+    let mut next_unplaced = 0;
+
+    let update = |node_index: u32, entering: bool, placed: &[bool], heap: &mut UnitHeap| {
+        let neighbors = edges[node_index as usize].iter().chain(&in_edges[node_index as usize]);
+        let siblings = in_edges[node_index as usize].iter().flat_map(|&in_neighbor| &edges[in_neighbor as usize]);
+        for &neighbor in neighbors.chain(siblings) {
+            if placed[neighbor as usize] {
+                continue;
+            }
+            if entering {
+                heap.increment(neighbor);
+            } else {
+                heap.decrement(neighbor);
+            }
+        }
+    };
+
+    let mut next = Some(start);
+    while order.len() < num_nodes {
+        let node_index = match next.take() {
+            Some(node_index) => node_index,
+            None => {
+                while placed[next_unplaced] {
+                    next_unplaced += 1;
+                }
+                next_unplaced as u32
+            }
+        };
+
+        placed[node_index as usize] = true;
+        heap.remove(node_index);
+        order.push(node_index);
+        update(node_index, true, &placed, &mut heap);
+        if order.len() > window {
+            let leaving = order[order.len() - window - 1];
+            update(leaving, false, &placed, &mut heap);
+        }
+
+        next = heap.max();
+    }
+
+    order
+}
+
+/// Unplaced nodes bucketed by their gorder score, the unit heap of the paper. Scores only ever
+/// move by one, so changing a score and finding the highest one are O(1), amortized for the latter.
+struct UnitHeap {
+    /// The nodes of every score above 0, which are the only ones gorder picks from.
+    buckets: Vec<Vec<u32>>,
+    positions: Vec<usize>,
+    scores: Vec<usize>,
+    top: usize,
+}
+
+impl UnitHeap {
+    fn new(num_nodes: usize) -> Self {
+        Self { buckets: vec![vec![]], positions: vec![0; num_nodes], scores: vec![0; num_nodes], top: 0 }
+    }
+
+    fn increment(&mut self, node_index: u32) {
+        self.unlink(node_index);
+        self.scores[node_index as usize] += 1;
+        self.link(node_index);
+    }
+
+    fn decrement(&mut self, node_index: u32) {
+        self.unlink(node_index);
+        self.scores[node_index as usize] -= 1;
+        self.link(node_index);
+    }
+
+    /// Takes a placed node out for good.
+    fn remove(&mut self, node_index: u32) {
+        self.unlink(node_index);
+        self.scores[node_index as usize] = 0;
+    }
+
+    /// A node of the highest score, if any node scores above 0.
+    fn max(&mut self) -> Option<u32> {
+        while self.top > 0 && self.buckets[self.top].is_empty() {
+            self.top -= 1;
+        }
+        self.buckets[self.top].last().copied().filter(|_| self.top > 0)
+    }
+
+    fn link(&mut self, node_index: u32) {
+        let score = self.scores[node_index as usize];
+        if score == 0 {
+            return;
+        }
+        if score == self.buckets.len() {
+            self.buckets.push(vec![]);
+        }
+        self.top = self.top.max(score);
+        self.positions[node_index as usize] = self.buckets[score].len();
+        self.buckets[score].push(node_index);
+    }
+
+    fn unlink(&mut self, node_index: u32) {
+        let score = self.scores[node_index as usize];
+        if score == 0 {
+            return;
+        }
+        let position = self.positions[node_index as usize];
+        let bucket = &mut self.buckets[score];
+        bucket.swap_remove(position);
+        if let Some(&moved) = bucket.get(position) {
+            self.positions[moved as usize] = position;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn medoid_is_the_vector_closest_to_the_mean() {
+        // The mean is (3.5, 2.75).
+        let vectors = vec![vec![0.0, 0.0], vec![10.0, 0.0], vec![4.0, 1.0], vec![0.0, 10.0]];
+        assert_eq!(medoid(vectors.iter().map(Vec::as_slice), 2), 2);
+    }
+
+    #[test]
+    fn gorder_places_neighbors_next_to_each_other() {
+        // Two triangles, linked by one edge from 2 to 3.
+        let edges = vec![vec![1, 2], vec![0, 2], vec![0, 1, 3], vec![4, 5], vec![3, 5], vec![3, 4]];
+        assert_eq!(gorder(&edges, 0, 2), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(gorder(&edges, 4, 2), vec![4, 3, 5, 1, 0, 2]);
+        // Without edges to follow, the remaining nodes are taken in index order.
+        assert_eq!(gorder(&[vec![], vec![], vec![]], 1, 1), vec![1, 0, 2]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use anyhow::Result;
+use memmap2::Mmap;
+
+use crate::Metric;
+
+/// Base vectors scanned per pass over the queries, small enough to stay in cache.
+const BLOCK_NUM_VECTORS: usize = 4096;
+
+/// An `.fbin` file: the number of vectors and the dimension as u32, then the vectors as f32.
+/// Mapped rather than read, so that files larger than memory can be scanned.
+pub(crate) struct Fbin {
+    mmap: Mmap,
+    pub(crate) num_vectors: usize,
+    pub(crate) vector_dim: usize,
+}
+
+impl Fbin {
+    pub(crate) fn open(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        anyhow::ensure!(mmap.len() >= 8, "{path} is too short for an .fbin header");
+        let num_vectors = u32::from_le_bytes(mmap[0..4].try_into().unwrap()) as usize;
+        let vector_dim = u32::from_le_bytes(mmap[4..8].try_into().unwrap()) as usize;
+        anyhow::ensure!(
+            mmap.len() == 8 + num_vectors * vector_dim * 4,
+            "{path} does not hold {num_vectors} vectors of {vector_dim} dimensions"
+        );
+        Ok(Self { mmap, num_vectors, vector_dim })
+    }
+
+    fn vectors(&self) -> &[f32] {
+        // The mapping is page aligned, so the vectors after the 8 byte header are f32 aligned.
+        let (prefix, vectors, _) = unsafe { self.mmap[8..].align_to::<f32>() };
+        assert!(prefix.is_empty());
+        vectors
+    }
+
+    pub(crate) fn vector(&self, index: usize) -> &[f32] {
+        &self.vectors()[index * self.vector_dim..(index + 1) * self.vector_dim]
+    }
+}
+
+/// Writes the exact `k` nearest base vectors of every query as `.ivecs`, and their distances as
+/// `.fvecs`, both in the order `read_ivecs` reads. Distances follow the canister: L2, 1 - cos, and
+/// -dot, so smaller is always closer.
+pub fn groundtruth(
+    base_path: &str,
+    query_path: &str,
+    k: usize,
+    metric: Metric,
+    num_threads: usize,
+    ivecs_path: &str,
+    fvecs_path: &str,
+) -> Result<()> {
+    let base = Fbin::open(base_path)?;
+    let queries = Fbin::open(query_path)?;
+    anyhow::ensure!(
+        base.vector_dim == queries.vector_dim,
+        "the base vectors have {} dimensions, but the queries have {}",
+        base.vector_dim,
+        queries.vector_dim
+    );
+    anyhow::ensure!(k > 0 && k <= base.num_vectors, "k must be between 1 and the number of base vectors");
+
+    println!("searching {} queries against {} vectors with {num_threads} threads..", queries.num_vectors, base.num_vectors);
+    let queries_per_thread = queries.num_vectors.div_ceil(num_threads.max(1)).max(1);
+    let results: Vec<Vec<(f32, u32)>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..queries.num_vectors)
+            .step_by(queries_per_thread)
+            .map(|start| {
+                let end = std::cmp::min(start + queries_per_thread, queries.num_vectors);
+                let base = &base;
+                let queries = &queries;
+                scope.spawn(move || {
+                    let query_vectors: Vec<&[f32]> = (start..end).map(|index| queries.vector(index)).collect();
+                    nearest(base, &query_vectors, k, metric)
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect()
+    });
+
+    let mut ivecs = BufWriter::new(File::create(ivecs_path)?);
+    let mut fvecs = BufWriter::new(File::create(fvecs_path)?);
+    for k_nn in results {
+        ivecs.write_all(&(k as u32).to_le_bytes())?;
+        fvecs.write_all(&(k as u32).to_le_bytes())?;
+        for (distance, index) in k_nn {
+            ivecs.write_all(&index.to_le_bytes())?;
+            fvecs.write_all(&distance.to_le_bytes())?;
+        }
+    }
+    ivecs.flush()?;
+    fvecs.flush()?;
+
+    Ok(())
+}
+
+/// The `k` nearest base vectors of every query, closest first.
+fn nearest(base: &Fbin, query_vectors: &[&[f32]], k: usize, metric: Metric) -> Vec<Vec<(f32, u32)>> {
+    let query_norms: Vec<f32> = query_vectors.iter().map(|query| dot(query, query).sqrt()).collect();
+    let mut k_nns: Vec<Vec<(f32, u32)>> = vec![Vec::with_capacity(k + 1); query_vectors.len()];
+
+    for block_start in (0..base.num_vectors).step_by(BLOCK_NUM_VECTORS) {
+        let block_end = std::cmp::min(block_start + BLOCK_NUM_VECTORS, base.num_vectors);
+        for (query_index, query) in query_vectors.iter().enumerate() {
+            let k_nn = &mut k_nns[query_index];
+            for base_index in block_start..block_end {
+                let vector = base.vector(base_index);
+                let distance = match metric {
+                    Metric::Euclidean => squared_euclidean(query, vector),
+                    Metric::Cosine => cosine_distance(dot(query, vector), query_norms[query_index] * dot(vector, vector).sqrt()),
+                    Metric::InnerProduct => -dot(query, vector),
+                };
+
+                if k_nn.len() == k && distance >= k_nn[k - 1].0 {
+                    continue;
+                }
+                let position = k_nn.partition_point(|&(kept, _)| kept <= distance);
+                k_nn.insert(position, (distance, base_index as u32));
+                k_nn.truncate(k);
+            }
+        }
+    }
+
+    if metric == Metric::Euclidean {
+        for k_nn in &mut k_nns {
+            for (distance, _) in k_nn.iter_mut() {
+                *distance = distance.sqrt();
+            }
+        }
+    }
+    k_nns
+}
+
+/// `1 - cosine similarity`, and 1 when either vector is zero, as the canister computes it.
+fn cosine_distance(dot: f32, norms: f32) -> f32 {
+    if norms == 0.0 {
+        1.0
+    } else {
+        1.0 - dot / norms
+    }
+}
+
+// Eight independent accumulators let the compiler vectorize the loops.
+pub(crate) fn squared_euclidean(a: &[f32], b: &[f32]) -> f32 {
+    let mut sums = [0.0; 8];
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let remainder: f32 = a_chunks.remainder().iter().zip(b_chunks.remainder()).map(|(x, y)| (x - y) * (x - y)).sum();
+    for (a_chunk, b_chunk) in a_chunks.zip(b_chunks) {
+        for ((sum, x), y) in sums.iter_mut().zip(a_chunk).zip(b_chunk) {
+            *sum += (x - y) * (x - y);
+        }
+    }
+    sums.iter().sum::<f32>() + remainder
+}
+
+pub(crate) fn dot(a: &[f32], b: &[f32]) -> f32 {
+    let mut sums = [0.0; 8];
+    let (a_chunks, b_chunks) = (a.chunks_exact(8), b.chunks_exact(8));
+    let remainder: f32 = a_chunks.remainder().iter().zip(b_chunks.remainder()).map(|(x, y)| x * y).sum();
+    for (a_chunk, b_chunk) in a_chunks.zip(b_chunks) {
+        for ((sum, x), y) in sums.iter_mut().zip(a_chunk).zip(b_chunk) {
+            *sum += x * y;
+        }
+    }
+    sums.iter().sum::<f32>() + remainder
+}
@@ -0,0 +1,63 @@
+use std::collections::HashMap;
+use std::fs::File;
+
+use anyhow::Result;
+use memmap2::MmapOptions;
+use ssd_vectune::graph_store::GraphStore;
+
+use crate::build::{self, FileStorage};
+use crate::{AttributeValue, Attributes, GraphLayout, LabelEntryPoint};
+
+/// As many as `initialize` accepts.
+const MAX_LABEL_ENTRY_POINTS: usize = 10_000;
+
+/// The medoid of the nodes of every text attribute, which `search_filtered` starts from when a
+/// filter requires that label.
+///
+/// With more labels than the canister accepts, the rarest ones are kept: a label shared by many
+/// nodes is reached from the global medoid anyway.
+pub fn label_entry_points(graph_path: &str, layout: &GraphLayout, attributes: &[(u32, Attributes)]) -> Result<Vec<LabelEntryPoint>> {
+    let mut label_nodes: HashMap<(&str, &str), Vec<u32>> = HashMap::new();
+    for (node_index, Attributes(attributes)) in attributes {
+        for (key, value) in attributes {
+            if let AttributeValue::Text(value) = value {
+                label_nodes.entry((key.as_str(), value.as_str())).or_default().push(*node_index);
+            }
+        }
+    }
+
+    let mut label_nodes: Vec<((&str, &str), Vec<u32>)> = label_nodes.into_iter().collect();
+    label_nodes.sort_by(|(a_label, a_nodes), (b_label, b_nodes)| a_nodes.len().cmp(&b_nodes.len()).then(a_label.cmp(b_label)));
+    if label_nodes.len() > MAX_LABEL_ENTRY_POINTS {
+        println!("keeping the {MAX_LABEL_ENTRY_POINTS} rarest of {} labels", label_nodes.len());
+        label_nodes.truncate(MAX_LABEL_ENTRY_POINTS);
+    }
+
+    // Copy on write, so that the graph file only has to be readable.
+    let file = File::open(graph_path)?;
+    let storage = FileStorage::new(unsafe { MmapOptions::new().map_copy(&file)? }, layout.sector_byte_size as usize);
+    let graph_store = GraphStore::new(
+        layout.num_vectors as usize,
+        layout.vector_dim as usize,
+        layout.edge_degrees as usize,
+        storage,
+    );
+
+    println!("finding the entry points of {} labels..", label_nodes.len());
+    let mut label_entry_points = Vec::with_capacity(label_nodes.len());
+    for ((key, value), node_indices) in label_nodes {
+        let node_indices: Vec<u32> = node_indices
+            .into_iter()
+            .filter(|&node_index| (node_index as u64) < layout.num_vectors)
+            .collect();
+        if node_indices.is_empty() {
+            continue;
+        }
+
+        let vectors: Vec<Vec<f32>> = node_indices.iter().map(|node_index| graph_store.read_node(node_index).0).collect();
+        let node_index = node_indices[build::medoid(vectors.iter().map(Vec::as_slice), layout.vector_dim as usize)];
+        label_entry_points.push(LabelEntryPoint { key: key.to_string(), value: value.to_string(), node_index });
+    }
+
+    Ok(label_entry_points)
+}
@@ -1,22 +1,28 @@
-use std::{fs::File, sync::Arc, time::Instant};
+mod build;
+mod groundtruth;
+mod labels;
+mod point;
+
+use std::{fs::File, io::Write, sync::Arc, time::Instant};
 
 use anyhow::Result;
-use bitvec::prelude::*;
-use bytesize::KIB;
-use candid::{Decode, Encode};
+use bytesize::{ByteSize, KIB};
+use candid::{CandidType, Decode, Encode};
 use ic_agent::{export::Principal, identity, Agent};
 use memmap2::Mmap;
 use ssd_vectune::{graph::GraphMetadata, original_vector_reader::{read_ivecs, OriginalVectorReader, OriginalVectorReaderTrait}};
 use tokio;
 use rand::{thread_rng, Rng};
 use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 
-//  cargo run --release --bin uploader -- upload  <graph path> <graph metadata path> <canister id> --name clankpan
+//  cargo run --release --bin uploader -- upload  <graph path> <graph metadata path> <graph header path> <canister id> --name clankpan
 
 enum UP {
     Done,
-    Continue(BitVec<u8>),
+    Continue(Vec<(u64, u64)>),
 }
 
 struct ChunkReader {
@@ -42,9 +48,129 @@ impl ChunkReader {
     pub fn file_size(&self) -> usize {
         self.mmap.len()
     }
+
+    pub fn num_chunks(&self) -> usize {
+        self.file_size().div_ceil(self.chunk_byte_size)
+    }
+
+    /// SHA-256 of every chunk concatenated in chunk order, as `upload_chunk_hashes` expects it.
+    pub fn chunk_hashes(&self) -> Vec<u8> {
+        self.mmap
+            .chunks(self.chunk_byte_size)
+            .flat_map(|chunk| Sha256::digest(chunk).to_vec())
+            .collect()
+    }
+}
+
+const HASH_BYTE_SIZE: usize = 32;
+
+fn index_digest(chunk_hashes: &[u8]) -> Vec<u8> {
+    Sha256::digest(chunk_hashes).to_vec()
+}
+
+const GRAPH_HEADER_MAGIC: &[u8; 8] = b"VECTUNE\0";
+/// The ssd-vectune graph layout written by this tool, see `header.rs` of the canister.
+const GRAPH_FORMAT_VERSION: u32 = 1;
+
+/// The header `start` checks the uploaded graph against, in the layout of `header.rs`.
+fn graph_header(layout: &GraphLayout, metric: Metric, index_digest: &[u8]) -> Vec<u8> {
+    let mut header = GRAPH_HEADER_MAGIC.to_vec();
+    header.extend_from_slice(&GRAPH_FORMAT_VERSION.to_le_bytes());
+    header.extend_from_slice(&(layout.vector_dim as u32).to_le_bytes());
+    header.extend_from_slice(&(layout.edge_degrees as u32).to_le_bytes());
+    header.extend_from_slice(&layout.sector_byte_size.to_le_bytes());
+    header.extend_from_slice(&layout.num_vectors.to_le_bytes());
+    header.extend_from_slice(&layout.medoid_node_index.to_le_bytes());
+    header.push(metric as u8);
+    header.extend_from_slice(&[0; 7]);
+    header.extend_from_slice(index_digest);
+    let checksum = Sha256::digest(&header);
+    header.extend_from_slice(&checksum);
+    header
+}
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+/// Replaces a value of the graph metadata file. Only for files whose metadata is known to be
+/// wrong; the result is still checked against the size of the graph file.
+#[derive(Args)]
+struct LayoutOverrides {
+    #[arg(long)]
+    override_medoid_node_index: Option<u32>,
+    #[arg(long)]
+    override_sector_byte_size: Option<u64>,
+    #[arg(long)]
+    override_num_vectors: Option<u64>,
+    #[arg(long)]
+    override_vector_dim: Option<u64>,
+    #[arg(long)]
+    override_edge_degrees: Option<u64>,
+}
+
+/// The parameters `GraphStore::new` reads the uploaded graph with.
+struct GraphLayout {
+    medoid_node_index: u32,
+    sector_byte_size: u64,
+    num_vectors: u64,
+    vector_dim: u64,
+    edge_degrees: u64,
+}
+
+impl GraphLayout {
+    fn new(graph_metadata: &GraphMetadata, overrides: &LayoutOverrides) -> Self {
+        fn pick<T: Copy + std::fmt::Display>(name: &str, from_metadata: T, overridden: Option<T>) -> T {
+            match overridden {
+                Some(value) => {
+                    println!("overriding {name}: {from_metadata} -> {value}");
+                    value
+                }
+                None => from_metadata,
+            }
+        }
+
+        Self {
+            medoid_node_index: pick("medoid_node_index", graph_metadata.medoid_node_index, overrides.override_medoid_node_index),
+            sector_byte_size: pick("sector_byte_size", graph_metadata.sector_byte_size as u64, overrides.override_sector_byte_size),
+            num_vectors: pick("num_vectors", graph_metadata.num_vectors as u64, overrides.override_num_vectors),
+            vector_dim: pick("vector_dim", graph_metadata.vector_dim as u64, overrides.override_vector_dim),
+            edge_degrees: pick("edge_degrees", graph_metadata.edge_degrees as u64, overrides.override_edge_degrees),
+        }
+    }
+
+    /// Fails when the graph file is not exactly `num_vectors` nodes of this layout.
+    fn check(&self, file_size: u64) -> Result<()> {
+        anyhow::ensure!(self.num_vectors > 0, "num_vectors is 0");
+        anyhow::ensure!(self.vector_dim > 0, "vector_dim is 0");
+        anyhow::ensure!(self.edge_degrees > 0, "edge_degrees is 0");
+        anyhow::ensure!(
+            (self.medoid_node_index as u64) < self.num_vectors,
+            "medoid_node_index {} is not below num_vectors {}",
+            self.medoid_node_index,
+            self.num_vectors
+        );
+
+        let byte_size = graph_byte_size(self.num_vectors, self.vector_dim, self.edge_degrees, self.sector_byte_size)?;
+        anyhow::ensure!(
+            file_size == byte_size,
+            "{} nodes of this layout take {byte_size} bytes, but the graph file has {file_size}",
+            self.num_vectors
+        );
+
+        Ok(())
+    }
 }
 
-use clap::{Parser, Subcommand};
+/// The bytes `num_vectors` nodes take in the layout `tool build` writes, as `header.rs` of the
+/// canister computes them: a node is its vector, its edges and the edge count, all 4 bytes wide,
+/// as many whole nodes as fit share a sector, and no node spans two sectors.
+fn graph_byte_size(num_vectors: u64, vector_dim: u64, edge_degrees: u64, sector_byte_size: u64) -> Result<u64> {
+    let node_byte_size = (vector_dim + edge_degrees + 1) * 4;
+    anyhow::ensure!(
+        node_byte_size <= sector_byte_size,
+        "a node of {vector_dim} dimensions and {edge_degrees} edges takes {node_byte_size} bytes, more than a sector of {sector_byte_size}"
+    );
+    Ok(num_vectors.div_ceil(sector_byte_size / node_byte_size) * sector_byte_size)
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -55,6 +181,47 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Executes the build process, including merge-index and gorder
+    Build {
+        /// Maximum number of out-edges of a node
+        #[arg(long, default_value = "90")]
+        edge_degrees: usize,
+
+        /// Pruning factor, larger values keep more long edges
+        #[arg(long, default_value = "2.0")]
+        alpha: f32,
+
+        /// Candidate list size while building
+        #[arg(long, default_value = "125")]
+        size_l: usize,
+
+        /// Vectors are indexed in shards which are merged afterwards. 1 builds a single graph
+        #[arg(long, default_value = "1")]
+        num_shards: usize,
+
+        #[arg(long, default_value = "5")]
+        gorder_window: usize,
+
+        #[arg(long, default_value = "4096")]
+        sector_byte_size: usize,
+
+        #[arg(long, default_value = "42")]
+        seed: u64,
+
+        /// The distance the graph is built with and the index is searched with, recorded in the graph header
+        #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+        metric: Metric,
+
+        /// Has to match `upload --chunk-kib-size`, the index digest of the header hashes these chunks
+        #[arg(long, default_value = "1024")]
+        chunk_kib_size: usize,
+
+        /// Base vectors in the `.fbin` format
+        source_path: String,
+        graph_path: String,
+        graph_metadata_path: String,
+        header_path: String,
+    },
+    /// Uploads a graph written by `build`, its metadata file and its header
     Upload {
         #[arg(long)]
         ic: bool,
@@ -62,11 +229,123 @@ enum Commands {
         #[arg(long, default_value = "default")]
         name: String,
     
+        /// Chunks smaller than half of an upload message are packed several per message
         #[arg(long, default_value = "1024")]
         chunk_kib_size: usize,
+
+        /// Uploads into the staging slot while the running index keeps serving, then promotes it
+        #[arg(long)]
+        staging: bool,
+
+        /// Tab separated `<id>\t<payload>` lines, one per node in graph order. Numeric ids are uploaded as nat64
+        #[arg(long)]
+        documents_path: Option<String>,
+
+        /// Tab separated `<key>=<value>` lines, one per node in graph order, for `search_filtered`.
+        /// Values which parse as numbers are uploaded as numbers, and every text value gets the medoid
+        /// of its nodes as an entry point
+        #[arg(long)]
+        attributes_path: Option<String>,
+
+        /// The distance the index is searched with
+        #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+        metric: Metric,
+
+        #[command(flatten)]
+        overrides: LayoutOverrides,
     
         source_data_path: String,
         graph_metadata_path: String,
+        /// Sent to the canister as `build` wrote it, to be checked against what `initialize` was given
+        header_path: String,
+        target_canister_id: String,
+    },
+    /// Prints the state of the canister and of its indexes
+    Status {
+        #[arg(long)]
+        ic: bool,
+
+        target_canister_id: String,
+    },
+    /// Compares the digest of the running index with the one of a local graph file
+    Verify {
+        #[arg(long)]
+        ic: bool,
+
+        source_data_path: String,
+        target_canister_id: String,
+    },
+    /// Drops the index of the canister so that another graph can be uploaded
+    Reset {
+        #[arg(long)]
+        ic: bool,
+
+        #[arg(long, default_value = "default")]
+        name: String,
+
+        target_canister_id: String,
+    },
+    /// Adds the vectors of an `.fbin` file to the running index, searchable right away
+    Insert {
+        #[arg(long)]
+        ic: bool,
+
+        #[arg(long, default_value = "default")]
+        name: String,
+
+        vectors_path: String,
+        target_canister_id: String,
+    },
+    /// Starts folding inserted and deleted nodes into the graph of the running index, and waits for it
+    Merge {
+        #[arg(long)]
+        ic: bool,
+
+        #[arg(long, default_value = "default")]
+        name: String,
+
+        /// Stops the merge in progress instead
+        #[arg(long)]
+        cancel: bool,
+
+        target_canister_id: String,
+    },
+    /// Removes nodes of the running index from search results, or returns them with `--undelete`
+    Delete {
+        #[arg(long)]
+        ic: bool,
+
+        #[arg(long, default_value = "default")]
+        name: String,
+
+        #[arg(long)]
+        undelete: bool,
+
+        target_canister_id: String,
+
+        /// Node indices, as `search` reports them
+        #[arg(required = true)]
+        node_indices: Vec<u32>,
+    },
+    /// Lists the principals allowed to manage the canister, after adding or removing one of them
+    Admins {
+        #[arg(long)]
+        ic: bool,
+
+        #[arg(long, default_value = "default")]
+        name: String,
+
+        /// Principal to give `--role` to
+        #[arg(long, conflicts_with = "remove")]
+        add: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = Role::Admin)]
+        role: Role,
+
+        /// Principal to take the role away from. Controllers stay admins regardless
+        #[arg(long)]
+        remove: Option<String>,
+
         target_canister_id: String,
     },
     Search {
@@ -74,10 +353,71 @@ enum Commands {
         ic: bool,
         #[arg(long)]
         simd: bool,
+        /// The metric the index is expected to be searched with
+        #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+        metric: Metric,
         #[arg(long, default_value = "./query_set/query.public.10K.fbin")]
         query_path: String,
         #[arg(long, default_value = "./query_set/gt/deep100M_groundtruth.ivecs")]
         ground_truth_path: String,
+        /// Number of queries sent in one `search_batch` call. With 1, `search` is called per query
+        #[arg(long, default_value = "1")]
+        batch_size: usize,
+        /// Searches with this dfx identity instead of anonymously, for indexes that are not public
+        #[arg(long)]
+        name: Option<String>,
+        /// Sends the searches as update calls, which count against a search quota. Switched on
+        /// by itself when the canister refuses a query call because of a quota
+        #[arg(long)]
+        update: bool,
+
+        target_canister_id: String,
+    },
+    /// Computes the exact nearest neighbors of queries by brute force, for measuring recall
+    Groundtruth {
+        #[arg(long, default_value = "100")]
+        k: usize,
+
+        #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+        metric: Metric,
+
+        /// Defaults to the number of available cores
+        #[arg(long)]
+        num_threads: Option<usize>,
+
+        /// Base vectors in the `.fbin` format
+        base_path: String,
+        /// Query vectors in the `.fbin` format
+        query_path: String,
+        /// Indices of the neighbors, as `tool search --ground-truth-path` reads them
+        ivecs_path: String,
+        /// Distances of the neighbors
+        fvecs_path: String,
+    },
+    /// Prints who may search the canister, after applying the given changes
+    Access {
+        #[arg(long)]
+        ic: bool,
+
+        #[arg(long, default_value = "default")]
+        name: String,
+
+        #[arg(long, value_enum)]
+        policy: Option<AccessPolicy>,
+
+        /// Searches per principal and window, for `--add-searcher`, or for everyone with `--policy`
+        #[arg(long, requires = "window_seconds")]
+        max_searches: Option<u64>,
+
+        #[arg(long, requires = "max_searches")]
+        window_seconds: Option<u64>,
+
+        /// Principal allowed to search under the allow-list policy
+        #[arg(long)]
+        add_searcher: Option<String>,
+
+        #[arg(long)]
+        remove_searcher: Option<String>,
 
         target_canister_id: String,
     },
@@ -89,58 +429,140 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Upload { ic, name, chunk_kib_size, source_data_path, graph_metadata_path, target_canister_id } => {
+        Commands::Build { edge_degrees, alpha, size_l, num_shards, gorder_window, sector_byte_size, seed, metric, chunk_kib_size, source_path, graph_path, graph_metadata_path, header_path } => {
+            let params = build::BuildParams {
+                edge_degrees,
+                alpha,
+                size_l,
+                num_shards,
+                gorder_window,
+                sector_byte_size,
+                seed,
+                metric,
+                chunk_byte_size: chunk_kib_size * KIB as usize,
+            };
+            build::build(&source_path, &graph_path, &graph_metadata_path, &header_path, &params)
+        },
+        Commands::Upload { ic, name, chunk_kib_size, staging, documents_path, attributes_path, metric, overrides, source_data_path, graph_metadata_path, header_path, target_canister_id } => {
 
             let agent = Arc::new(get_agent(&name, ic).await?);
             let target_canister_id = Principal::from_text(target_canister_id)?;
             let chunk_byte_size = chunk_kib_size * KIB as usize;
         
             let chunk_reader = Arc::new(ChunkReader::new(&source_data_path, chunk_byte_size)?);
+            let chunks_per_batch = std::cmp::max(1, UPLOAD_BATCH_BYTE_SIZE / chunk_byte_size);
             let graph_metadata = GraphMetadata::load(&graph_metadata_path).unwrap();
         
-            let num_chunks = (chunk_reader.file_size() + chunk_byte_size - 1) / chunk_byte_size;
+            let num_chunks = chunk_reader.num_chunks();
         
             assert!(chunk_reader.file_size() <= num_chunks*chunk_byte_size);
         
-            println!("graph_metadata.edge_degrees {}", graph_metadata.edge_degrees);
-        
+            let layout = GraphLayout::new(&graph_metadata, &overrides);
+            layout.check(chunk_reader.file_size() as u64)?;
+            println!(
+                "layout: {} vectors of {} dimensions, {} edges, {} byte sectors, medoid {}",
+                layout.num_vectors, layout.vector_dim, layout.edge_degrees, layout.sector_byte_size, layout.medoid_node_index
+            );
         
-            println!("calling status_code..");
-            match call_status_code(&agent, target_canister_id).await? {
         
-                0 => {
-                    println!("calling initialize..");
-                    call_initialize(
-                        &agent,
-                        target_canister_id,
-                        num_chunks as u64,
-                        chunk_byte_size as u64,
-                        graph_metadata.medoid_node_index,
-                        graph_metadata.sector_byte_size as u64,
-                        graph_metadata.num_vectors as u64,
-                        graph_metadata.vector_dim as u64,
-                        // graph_metadata.edge_degrees as u64,
-                        90
-                    )
-                    .await?;
-                },
-                1 => {
-                    println!("skip call_initialize")
-                },
-                2 => {
-                    todo!()
+            println!("calling status..");
+            let status = call_status(&agent, target_canister_id).await?;
+            let need_initialize = if staging {
+                match status.state {
+                    State::Running => true,
+                    State::Staging => {
+                        println!("skip call_initialize_staging");
+                        false
+                    },
+                    state => anyhow::bail!("--staging needs a running index, but the canister is {state:?}"),
+                }
+            } else {
+                match status.state {
+                    State::None => true,
+                    State::Loading => {
+                        println!("skip call_initialize");
+                        false
+                    },
+                    State::Running => {
+                        if !confirm("the canister is already running an index. reset it and upload again?")? {
+                            return Ok(())
+                        }
+                        println!("calling reset..");
+                        call_reset(&agent, target_canister_id).await?;
+                        true
+                    },
+                    State::Staging => anyhow::bail!("the canister is staging another index, run with --staging to resume it"),
+                }
+            };
+
+            let attributes = match &attributes_path {
+                Some(attributes_path) => {
+                    let attributes = read_attributes(attributes_path)?;
+                    anyhow::ensure!(
+                        attributes.len() as u64 <= layout.num_vectors,
+                        "{attributes_path} has more lines than the graph has nodes"
+                    );
+                    attributes
                 },
-                _ => todo!()
+                None => vec![],
+            };
+
+            println!("hashing chunks..");
+            let chunk_hashes = chunk_reader.chunk_hashes();
+            let index_digest = index_digest(&chunk_hashes);
+
+            let num_chunk_hashes = if need_initialize {
+                let label_entry_points = labels::label_entry_points(&source_data_path, &layout, &attributes)?;
+
+                println!("calling initialize..");
+                call_initialize(
+                    &agent,
+                    target_canister_id,
+                    num_chunks as u64,
+                    chunk_byte_size as u64,
+                    index_digest.clone(),
+                    layout.medoid_node_index,
+                    layout.sector_byte_size,
+                    layout.num_vectors,
+                    layout.vector_dim,
+                    layout.edge_degrees,
+                    metric,
+                    label_entry_points,
+                    staging,
+                )
+                .await?;
+
+                0
+            } else {
+                let loading = status.loading.as_ref().expect("a loading canister reports its upload");
+                anyhow::ensure!(
+                    loading.index.index_digest == index_digest,
+                    "the canister is uploading another graph than {source_data_path}"
+                );
+                loading.num_chunk_hashes as usize
+            };
+
+            // The hashes of a large graph do not fit into one request.
+            let hashes_per_batch = UPLOAD_BATCH_BYTE_SIZE / HASH_BYTE_SIZE;
+            let missing_chunk_hashes = &chunk_hashes[num_chunk_hashes * HASH_BYTE_SIZE..];
+            for (batch_index, batch) in missing_chunk_hashes.chunks(hashes_per_batch * HASH_BYTE_SIZE).enumerate() {
+                let first_chunk_index = num_chunk_hashes + batch_index * hashes_per_batch;
+                println!("calling upload_chunk_hashes.. from chunk {first_chunk_index}");
+                call_upload_chunk_hashes(&agent, target_canister_id, first_chunk_index as u64, batch).await?;
             }
+
+            println!("calling upload_header..");
+            let header = std::fs::read(&header_path)?;
+            call_upload_header(&agent, target_canister_id, &header).await?;
         
             println!("start loop");
         
-            while let UP::Continue(uploaded_chunks) = {
-                println!("calling missing_chunks...");
-                let uploaded_chunks = get_missing_chunks(&agent, target_canister_id).await?;
-                let missing_counts = uploaded_chunks.iter().filter(|bit| !**bit).count();
+            while let UP::Continue(missing_chunk_ranges) = {
+                println!("calling missing_chunk_ranges...");
+                let (missing_chunk_ranges, num_chunks) = get_missing_chunk_ranges(&agent, target_canister_id).await?;
+                let missing_counts: u64 = missing_chunk_ranges.iter().map(|(start, end)| end - start).sum();
         
-                assert!(chunk_reader.file_size() <= uploaded_chunks.len() * chunk_byte_size);
+                assert!(chunk_reader.file_size() <= num_chunks as usize * chunk_byte_size);
         
         
                 match missing_counts {
@@ -149,54 +571,262 @@ async fn main() -> Result<()> {
                     },
                     _ => {
                         println!("missing_counts: {missing_counts}");
-                        UP::Continue(uploaded_chunks)
+                        UP::Continue(missing_chunk_ranges)
                     },
                 }
             } {
         
-                let uploaded_chunks_len = uploaded_chunks.len();
+                let uploaded_chunks_len = num_chunks;
+
+                let missing_chunk_indices: Vec<usize> = missing_chunk_ranges
+                    .into_iter()
+                    .flat_map(|(start, end)| start as usize..end as usize)
+                    .collect();
+                let chunk_batches: Vec<Vec<usize>> = missing_chunk_indices
+                    .chunks(chunks_per_batch)
+                    .map(|chunk_indices| chunk_indices.to_vec())
+                    .collect();
         
-                let task_stream = stream::iter(
-                    uploaded_chunks
-                        .into_iter()
-                        .enumerate()
-                        .filter_map(|(index, bit)| if !bit { Some(index) } else { None }),
-                )
-                .map(|chunk_index| {
+                let task_stream = stream::iter(chunk_batches)
+                .map(|chunk_indices| {
                     let agent = agent.clone();
                     let chunk_reader = chunk_reader.clone();
                     tokio::spawn(async move {
                         // Load chunk data from disk
-                        let chunk_byte_data = chunk_reader.read(chunk_index);
+                        let chunks: Vec<(u64, Vec<u8>)> = chunk_indices
+                            .iter()
+                            .map(|&chunk_index| (chunk_index as u64, chunk_reader.read(chunk_index)))
+                            .collect();
         
-                        // upload chunk into canister
-                        let response = call_upload_chunk(
-                            &agent,
-                            target_canister_id,
-                            (chunk_byte_data, chunk_index as u64),
-                        )
-                        .await;
-                        println!("chunk_index: {chunk_index}/{uploaded_chunks_len}");
-                        match response {
-                            Ok(_) => {},
-                            Err(err) => {
-                                println!("{:?}", err);
-                            }
-                        }
+                        // upload chunks into canister
+                        let response = call_upload_chunks(&agent, target_canister_id, &chunks).await;
+                        let last_chunk_index = chunk_indices[chunk_indices.len() - 1];
+                        println!("chunk_index: {last_chunk_index}/{uploaded_chunks_len}");
+                        response
                     })
                 });
         
-                let _results: Vec<_> = task_stream.buffered(20).collect().await;
+                let results: Vec<_> = task_stream.buffered(20).collect().await;
+                for result in results {
+                    match result? {
+                        Ok(_) => {},
+                        // The canister refused the chunks, so uploading them again fails the same way.
+                        Err(err) if err.downcast_ref::<VectuneError>().is_some() => return Err(err),
+                        // Transport and agent errors are retried with the chunks still missing.
+                        Err(err) => {
+                            println!("{:?}", err);
+                        }
+                    }
+                }
+            }
+
+            if let Some(documents_path) = documents_path {
+                let documents = read_documents(&documents_path)?;
+                anyhow::ensure!(
+                    documents.len() as u64 <= layout.num_vectors,
+                    "{documents_path} has more lines than the graph has nodes"
+                );
+
+                for batch in document_batches(documents) {
+                    println!("calling upload_documents.. {} documents", batch.len());
+                    call_upload_documents(&agent, target_canister_id, &batch).await?;
+                }
+            }
+
+            for batch in attribute_batches(attributes) {
+                println!("calling upload_attributes.. {} nodes", batch.len());
+                call_upload_attributes(&agent, target_canister_id, &batch).await?;
+            }
+
+            if staging {
+                println!("calling promote..");
+                call_promote(&agent, target_canister_id).await?;
+            } else {
+                println!("calling start..");
+                call_start(&agent, target_canister_id).await?;
             }
         
-        
             Ok(())
         },
-        Commands::Search { ic, simd, query_path, ground_truth_path, target_canister_id } => {
+        Commands::Status { ic, target_canister_id } => {
+            let agent = get_anonymous_agent(ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            let status = call_status(&agent, target_canister_id).await?;
+            print_status(&status);
+
+            Ok(())
+        },
+        Commands::Verify { ic, source_data_path, target_canister_id } => {
+            let agent = get_anonymous_agent(ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            println!("calling index_digest..");
+            let canister_digest = call_index_digest(&agent, target_canister_id).await?;
+            if canister_digest.chunk_byte_size == 0 {
+                anyhow::bail!("no digest recorded, the running index was uploaded before digests existed");
+            }
+
+            println!("hashing chunks..");
+            let chunk_reader = ChunkReader::new(&source_data_path, canister_digest.chunk_byte_size as usize)?;
+            let local_digest = index_digest(&chunk_reader.chunk_hashes());
+
+            println!("canister digest: {}", to_hex(&canister_digest.digest));
+            println!("local digest:    {}", to_hex(&local_digest));
+
+            if canister_digest.digest != local_digest {
+                anyhow::bail!("the running index does not match {source_data_path}");
+            }
+            println!("the running index matches {source_data_path}");
+
+            Ok(())
+        },
+        Commands::Reset { ic, name, target_canister_id } => {
+            let agent = get_agent(&name, ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            println!("calling reset..");
+            call_reset(&agent, target_canister_id).await?;
+
+            Ok(())
+        },
+        Commands::Insert { ic, name, vectors_path, target_canister_id } => {
+            let agent = get_agent(&name, ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            let reader = OriginalVectorReader::new(&vectors_path)?;
+            let num_vectors = reader.get_num_vectors();
+            for batch_start in (0..num_vectors).step_by(INSERT_BATCH_SIZE) {
+                let batch_end = std::cmp::min(batch_start + INSERT_BATCH_SIZE, num_vectors);
+                let vectors: Vec<Vec<f32>> = (batch_start..batch_end)
+                    .map(|index| reader.read(&index))
+                    .collect::<Result<_>>()?;
+
+                println!("calling insert.. vectors {batch_start}..{batch_end}/{num_vectors}");
+                let node_indices = call_insert(&agent, target_canister_id, &vectors).await?;
+                if let (Some(first), Some(last)) = (node_indices.first(), node_indices.last()) {
+                    println!("inserted as nodes {first}..={last}");
+                }
+            }
+
+            Ok(())
+        },
+        Commands::Merge { ic, name, cancel, target_canister_id } => {
+            let agent = get_agent(&name, ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            if cancel {
+                println!("calling cancel_merge..");
+                call_merge(&agent, target_canister_id, cancel).await?;
+                println!("cancelled");
+                return Ok(());
+            }
+
+            println!("calling merge..");
+            call_merge(&agent, target_canister_id, cancel).await?;
+
+            while let Some(merge) = call_status(&agent, target_canister_id).await?.merge {
+                match merge.phase {
+                    MergePhase::Deletes { next_node_index } => {
+                        println!("unlinking {} deleted nodes.. {next_node_index} nodes visited", merge.num_deletes)
+                    }
+                    MergePhase::Writes { next_delta_index } => {
+                        println!("writing inserted nodes.. {next_delta_index}/{}", merge.num_inserts)
+                    }
+                    MergePhase::Inserts { next_delta_index } => {
+                        println!("linking inserted nodes.. {next_delta_index}/{}", merge.num_inserts)
+                    }
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+            println!("merged");
+
+            Ok(())
+        },
+        Commands::Delete { ic, name, undelete, target_canister_id, node_indices } => {
+            let agent = get_agent(&name, ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            let method_name = if undelete { "undelete" } else { "delete" };
+            println!("calling {method_name}.. {} nodes", node_indices.len());
+            call_delete(&agent, target_canister_id, &node_indices, undelete).await?;
+
+            let status = call_status(&agent, target_canister_id).await?;
+            println!("deleted nodes: {}", status.deleted_count);
+
+            Ok(())
+        },
+        Commands::Admins { ic, name, add, role, remove, target_canister_id } => {
+            let agent = get_agent(&name, ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            if let Some(principal) = add {
+                println!("calling add_admin..");
+                call_add_admin(&agent, target_canister_id, Principal::from_text(principal)?, role).await?;
+            }
+            if let Some(principal) = remove {
+                println!("calling remove_admin..");
+                call_remove_admin(&agent, target_canister_id, Principal::from_text(principal)?).await?;
+            }
+
+            for (principal, role) in call_admins(&agent, target_canister_id).await? {
+                println!("{principal}\t{role:?}");
+            }
+
+            Ok(())
+        },
+        Commands::Groundtruth { k, metric, num_threads, base_path, query_path, ivecs_path, fvecs_path } => {
+            let num_threads = match num_threads {
+                Some(num_threads) => num_threads,
+                None => std::thread::available_parallelism()?.get(),
+            };
+            groundtruth::groundtruth(&base_path, &query_path, k, metric, num_threads, &ivecs_path, &fvecs_path)
+        },
+        Commands::Access { ic, name, policy, max_searches, window_seconds, add_searcher, remove_searcher, target_canister_id } => {
+            let agent = get_agent(&name, ic).await?;
+            let target_canister_id = Principal::from_text(target_canister_id)?;
+
+            let quota = max_searches
+                .zip(window_seconds)
+                .map(|(max_searches, window_seconds)| Quota { max_searches, window_seconds });
+
+            if let Some(policy) = policy {
+                println!("calling set_access..");
+                call_set_access(&agent, target_canister_id, policy, quota).await?;
+            }
+            if let Some(principal) = add_searcher {
+                println!("calling add_searcher..");
+                let searcher_quota = if policy.is_some() { None } else { quota };
+                call_add_searcher(&agent, target_canister_id, Principal::from_text(principal)?, searcher_quota).await?;
+            }
+            if let Some(principal) = remove_searcher {
+                println!("calling remove_searcher..");
+                call_remove_searcher(&agent, target_canister_id, Principal::from_text(principal)?).await?;
+            }
+
+            let (access, searchers) = call_access(&agent, target_canister_id).await?;
+            println!("policy: {:?}", access.policy);
+            println!("quota:  {}", format_quota(access.quota));
+            for (principal, searcher) in searchers {
+                println!("{principal}\t{}", format_quota(searcher.quota));
+            }
+
+            Ok(())
+        },
+        Commands::Search { ic, simd, metric, query_path, ground_truth_path, batch_size, name, mut update, target_canister_id } => {
 
             let target_canister_id = Principal::from_text(target_canister_id)?;
+            anyhow::ensure!(batch_size > 0, "--batch-size must be at least 1");
+
+            let agent = match name {
+                Some(name) => Arc::new(get_agent(&name, ic).await?),
+                None => Arc::new(get_anonymous_agent(ic).await?),
+            };
 
-            let agent = Arc::new(get_anonymous_agent(ic).await?);
+            let canister_metric = call_metric(&agent, target_canister_id).await?;
+            if canister_metric != metric {
+                anyhow::bail!("the index is searched with {canister_metric:?}, not {metric:?}");
+            }
 
             let query_vector_reader = OriginalVectorReader::new(&query_path)?;
             let groundtruth: Vec<Vec<u32>> = read_ivecs(&ground_truth_path).unwrap();
@@ -204,34 +834,59 @@ async fn main() -> Result<()> {
             let query_iter = 100;
             let mut total_time = 0;
             let mut rng = thread_rng();
+            let random_query_indices: Vec<usize> = (0..query_iter)
+                .map(|_| rng.gen_range(0..query_vector_reader.get_num_vectors()))
+                .collect();
         
             let mut hit_sum = 0;
-            for query_index in 0..query_iter {
-                let random_query_index  = rng.gen_range(0..query_vector_reader.get_num_vectors());
-                let query_vector: Vec<f32> = query_vector_reader.read(&random_query_index).unwrap();
-                // let query_vector: Vec<f32> = query_vector_reader.read(&query_index).unwrap();
-                println!("query_index {query_index}");
+            let mut query_index = 0;
+            while query_index < query_iter {
+                let batch_end = std::cmp::min(query_index + batch_size, query_iter);
+                let query_vectors: Vec<Vec<f32>> = random_query_indices[query_index..batch_end]
+                    .iter()
+                    .map(|random_query_index| query_vector_reader.read(random_query_index).unwrap())
+                    .collect();
         
                 let start = Instant::now();
         
-                let k_ann = call_search(&agent, target_canister_id, &query_vector, simd).await?;
+                let response = if batch_size == 1 {
+                    call_search(&agent, target_canister_id, &query_vectors[0], simd, update).await.map(|k_ann| vec![k_ann])
+                } else {
+                    // The canister may answer fewer queries than asked to stay within the instruction limit.
+                    call_search_batch(&agent, target_canister_id, &query_vectors, simd, update).await
+                };
+                let k_anns = match response {
+                    Err(err) if !update && matches!(err.downcast_ref::<VectuneError>(), Some(VectuneError::ReplicatedCallRequired)) => {
+                        println!("the caller has a search quota, searching with update calls");
+                        update = true;
+                        continue;
+                    },
+                    response => response?,
+                };
+                anyhow::ensure!(!k_anns.is_empty(), "search_batch answered no query");
         
                 let t = start.elapsed().as_millis();
                 total_time += t;
         
-                let result_top_5: Vec<u32> = k_ann.into_iter().map(|(_, i)| i).collect();
-                let top5_groundtruth = &groundtruth[random_query_index][0..5];
-                println!("{:?}\n{:?}", top5_groundtruth, result_top_5);
-                let mut hit = 0;
-                for res in result_top_5 {
-                    if top5_groundtruth.contains(&res) {
-                        hit += 1;
+                for k_ann in k_anns {
+                    let random_query_index = random_query_indices[query_index];
+                    println!("query_index {query_index}");
+
+                    let result_top_5: Vec<u32> = k_ann.into_iter().map(|(_, i)| i).collect();
+                    let top5_groundtruth = &groundtruth[random_query_index][0..5];
+                    println!("{:?}\n{:?}", top5_groundtruth, result_top_5);
+                    let mut hit = 0;
+                    for res in result_top_5 {
+                        if top5_groundtruth.contains(&res) {
+                            hit += 1;
+                        }
                     }
-                }
-                hit_sum += hit;
-        
-                println!("hit: {}/{}\n", hit, top5_groundtruth.len());
+                    hit_sum += hit;
         
+                    println!("hit: {}/{}\n", hit, top5_groundtruth.len());
+
+                    query_index += 1;
+                }
             }
 
             println!("average query-time:  {} ms", total_time as f32 / query_iter as f32);
@@ -281,34 +936,183 @@ async fn get_anonymous_agent(is_ic: bool) -> Result<Agent> {
     Ok(agent)
 }
 
+/// Calls a search endpoint as a query, or its `_update` variant which counts against a quota.
+async fn call_search_endpoint(
+    agent: &Agent,
+    target_canister_id: Principal,
+    method_name: &str,
+    arg: Vec<u8>,
+    update: bool,
+) -> Result<Vec<u8>> {
+    let response = if update {
+        agent
+            .update(&target_canister_id, format!("{method_name}_update"))
+            .with_arg(arg)
+            .call_and_wait()
+            .await?
+    } else {
+        agent.query(&target_canister_id, method_name).with_arg(arg).call().await?
+    };
+
+    Ok(response)
+}
+
 async fn call_search(
     agent: &Agent,
     target_canister_id: Principal,
     query_vector: &Vec<f32>,
     simd: bool,
+    update: bool,
 ) -> Result<Vec<(f32, u32)>> {
     let method_name = if simd { "search_with_simd" } else { "search" };
     let top_k: u64 = 5;
     let size_l: u64 = 100;
-    let response = agent
-        .query(&target_canister_id, method_name)
-        .with_arg(Encode!(query_vector, &top_k, &size_l)?)
-        .call()
-        .await?;
-    let status_code = Decode!(&response, Vec<(f32, u32)>)?;
+    let arg = Encode!(query_vector, &top_k, &size_l)?;
+    let response = call_search_endpoint(agent, target_canister_id, method_name, arg, update).await?;
+    let k_ann = Decode!(&response, Result<Vec<(f32, u32)>, VectuneError>)??;
+
+    Ok(k_ann)
+}
+
+async fn call_search_batch(
+    agent: &Agent,
+    target_canister_id: Principal,
+    query_vectors: &[Vec<f32>],
+    simd: bool,
+    update: bool,
+) -> Result<Vec<Vec<(f32, u32)>>> {
+    let method_name = if simd { "search_batch_with_simd" } else { "search_batch" };
+    let top_k: u64 = 5;
+    let size_l: u64 = 100;
+    let arg = Encode!(&query_vectors, &top_k, &size_l)?;
+    let response = call_search_endpoint(agent, target_canister_id, method_name, arg, update).await?;
+    let k_anns = Decode!(&response, Result<Vec<Vec<(f32, u32)>>, VectuneError>)??;
+
+    Ok(k_anns)
+}
+
+async fn call_metric(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<Metric> {
+    let method_name = "metric";
+    let response = agent.query(&target_canister_id, method_name).with_arg(Encode!()?).call().await?;
+    let metric = Decode!(&response, Result<Metric, VectuneError>)??;
+
+    Ok(metric)
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum Slot {
+    Blue,
+    Green,
+}
+
+#[derive(CandidType, Deserialize)]
+struct RunningMetadata {
+    slot: Slot,
+    chunk_byte_size: u64,
+    index_digest: Vec<u8>,
+    created_at: u64,
+    medoid_node_index: u32,
+    sector_byte_size: u64,
+    num_vectors: u64,
+    vector_dim: u64,
+    edge_degrees: u64,
+    metric: Metric,
+    label_entry_points: Vec<LabelEntryPoint>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LabelEntryPoint {
+    key: String,
+    value: String,
+    node_index: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+enum MergePhase {
+    Deletes { next_node_index: u64 },
+    Writes { next_delta_index: u64 },
+    Inserts { next_delta_index: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+struct MergeProgress {
+    num_deletes: u64,
+    num_inserts: u64,
+    phase: MergePhase,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LoadingStatus {
+    index: RunningMetadata,
+    num_uploaded_chunks: u64,
+    num_chunks: u64,
+    num_chunk_hashes: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Status {
+    state: State,
+    running: Option<RunningMetadata>,
+    loading: Option<LoadingStatus>,
+    deleted_count: u64,
+    inserted_count: u64,
+    merge: Option<MergeProgress>,
+    stable_memory_byte_size: u64,
+    version: String,
+}
 
-    Ok(status_code)
+fn print_status(status: &Status) {
+    println!("state:         {:?}", status.state);
+    println!("version:       {}", status.version);
+    println!("stable memory: {}", ByteSize(status.stable_memory_byte_size));
+
+    if let Some(running) = &status.running {
+        println!("\nrunning index");
+        print_index(running);
+        println!("  deleted nodes:     {}", status.deleted_count);
+        println!("  inserted nodes:    {}", status.inserted_count);
+        if status.merge.is_some() {
+            println!("  merging:           yes");
+        }
+    }
+    if let Some(loading) = &status.loading {
+        println!("\nuploading index");
+        print_index(&loading.index);
+        println!(
+            "  uploaded chunks:   {}/{} ({:.1} %)",
+            loading.num_uploaded_chunks,
+            loading.num_chunks,
+            loading.num_uploaded_chunks as f64 / loading.num_chunks.max(1) as f64 * 100.0
+        );
+    }
+}
+
+fn print_index(index: &RunningMetadata) {
+    println!("  slot:              {:?}", index.slot);
+    println!("  created at:        {} (unix time)", index.created_at / 1_000_000_000);
+    println!("  metric:            {:?}", index.metric);
+    println!("  num vectors:       {}", index.num_vectors);
+    println!("  vector dim:        {}", index.vector_dim);
+    println!("  edge degrees:      {}", index.edge_degrees);
+    println!("  medoid node index: {}", index.medoid_node_index);
+    println!("  label entries:     {}", index.label_entry_points.len());
+    println!("  sector byte size:  {}", index.sector_byte_size);
+    println!("  chunk byte size:   {}", index.chunk_byte_size);
+    println!("  index digest:      {}", to_hex(&index.index_digest));
 }
 
-async fn call_status_code(
+async fn call_status(
     agent: &Agent,
     target_canister_id: Principal,
-) -> Result<u8> {
-    let method_name = "status_code";
+) -> Result<Status> {
+    let method_name = "status";
     let response = agent.query(&target_canister_id, method_name).with_arg(Encode!()?).call().await?;
-    let status_code = Decode!(&response, u8)?;
+    let status = Decode!(&response, Status)?;
 
-    Ok(status_code)
+    Ok(status)
 }
 
 async fn call_initialize(
@@ -317,70 +1121,673 @@ async fn call_initialize(
 
     num_chunks: u64,
     chunk_byte_size: u64,
+    index_digest: Vec<u8>,
     medoid_node_index: u32,
     sector_byte_size: u64,
     num_vectors: u64,
     vector_dim: u64,
     edge_degrees: u64,
+    metric: Metric,
+    label_entry_points: Vec<LabelEntryPoint>,
+    staging: bool,
 ) -> Result<()> {
-    let method_name = "initialize";
-    let _ = agent
+    let method_name = if staging { "initialize_staging" } else { "initialize" };
+    let response = agent
         .update(&target_canister_id, method_name)
         .with_arg(Encode!(
             &num_chunks,
             &chunk_byte_size,
+            &index_digest,
             &medoid_node_index,
             &sector_byte_size,
             &num_vectors,
             &vector_dim,
-            &edge_degrees
+            &edge_degrees,
+            &metric,
+            &label_entry_points
         )?)
         .call_and_wait()
         .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+enum State {
+    None,
+    Loading,
+    Running,
+    Staging,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum VectuneError {
+    NotOwner,
+    WrongState { expected: Vec<State>, actual: State },
+    InvalidArgument(String),
+    ChunkOutOfRange { chunk_index: u64, num_chunks: u64 },
+    ChunkHashMismatch { chunk_index: u64 },
+    ChunkHashMissing { chunk_index: u64 },
+    ChunkConflict { chunk_index: u64 },
+    UploadIncomplete { missing_chunks: u64 },
+    DigestMismatch,
+    DimensionMismatch { expected: u64, actual: u64 },
+    InvalidHeader(String),
+    OutOfMemory,
+    SearchNotAllowed,
+    ReplicatedCallRequired,
+    QuotaExceeded { retry_after_seconds: u64 },
+    DeltaIndexFull { max_nodes: u64 },
+    MergeInProgress,
+    MetricNotSupported { metric: Metric },
+}
+
+impl std::fmt::Display for VectuneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VectuneError::NotOwner => write!(f, "the caller is not allowed to call this endpoint"),
+            VectuneError::WrongState { expected, actual } => {
+                write!(f, "the canister is {actual:?}, but has to be one of {expected:?}")
+            }
+            VectuneError::InvalidArgument(message) => write!(f, "invalid argument: {message}"),
+            VectuneError::ChunkOutOfRange { chunk_index, num_chunks } => {
+                write!(f, "chunk {chunk_index} is out of range, the index has {num_chunks} chunks")
+            }
+            VectuneError::ChunkHashMismatch { chunk_index } => write!(f, "chunk {chunk_index} does not match its hash"),
+            VectuneError::ChunkHashMissing { chunk_index } => write!(f, "the hash of chunk {chunk_index} is not uploaded yet"),
+            VectuneError::ChunkConflict { chunk_index } => {
+                write!(f, "chunk {chunk_index} was already uploaded with different bytes")
+            }
+            VectuneError::UploadIncomplete { missing_chunks } => write!(f, "{missing_chunks} chunks are not uploaded yet"),
+            VectuneError::DigestMismatch => write!(f, "the chunk hashes do not match the index digest"),
+            VectuneError::DimensionMismatch { expected, actual } => {
+                write!(f, "the vector has {actual} dimensions, but the index has {expected}")
+            }
+            VectuneError::InvalidHeader(message) => write!(f, "invalid graph header: {message}"),
+            VectuneError::OutOfMemory => write!(f, "the canister ran out of stable memory"),
+            VectuneError::SearchNotAllowed => write!(f, "the caller is not allowed to search this index"),
+            VectuneError::ReplicatedCallRequired => {
+                write!(f, "the caller has a search quota, which only the update search endpoints count")
+            }
+            VectuneError::QuotaExceeded { retry_after_seconds } => {
+                write!(f, "the search quota is used up, retry in {retry_after_seconds}s")
+            }
+            VectuneError::DeltaIndexFull { max_nodes } => {
+                write!(f, "the delta index holds up to {max_nodes} inserted nodes")
+            }
+            VectuneError::MergeInProgress => write!(f, "a merge is emptying the delta index, insert again once it is done"),
+            VectuneError::MetricNotSupported { metric } => write!(f, "insert and merge do not support indexes searched with {metric:?}"),
+        }
+    }
+}
+
+impl std::error::Error for VectuneError {}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, ValueEnum)]
+enum AccessPolicy {
+    Public,
+    AllowList,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+struct Quota {
+    max_searches: u64,
+    window_seconds: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Access {
+    policy: AccessPolicy,
+    quota: Option<Quota>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Searcher {
+    quota: Option<Quota>,
+}
+
+fn format_quota(quota: Option<Quota>) -> String {
+    match quota {
+        Some(Quota { max_searches, window_seconds }) => format!("{max_searches} searches per {window_seconds}s"),
+        None => "unlimited".to_string(),
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, ValueEnum)]
+enum Role {
+    Uploader,
+    Admin,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug, ValueEnum)]
+enum Metric {
+    Euclidean,
+    Cosine,
+    InnerProduct,
+}
+
+#[derive(CandidType, Deserialize)]
+enum DocumentId {
+    Text(String),
+    Nat64(u64),
+}
+
+#[derive(CandidType, Deserialize)]
+struct Document {
+    id: DocumentId,
+    payload: Option<Vec<u8>>,
+}
+
+/// Upload requests are limited to 2 MiB, so leave room for the Candid encoding.
+const UPLOAD_BATCH_BYTE_SIZE: usize = 1536 * KIB as usize;
+
+fn read_documents(path: &str) -> Result<Vec<(u32, Document)>> {
+    let documents = std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(node_index, line)| {
+            let (id, payload) = match line.split_once('\t') {
+                Some((id, payload)) => (id, Some(payload.as_bytes().to_vec())),
+                None => (line, None),
+            };
+            let id = match id.parse::<u64>() {
+                Ok(id) => DocumentId::Nat64(id),
+                Err(_) => DocumentId::Text(id.to_string()),
+            };
+            (node_index as u32, Document { id, payload })
+        })
+        .collect();
+
+    Ok(documents)
+}
+
+fn document_batches(documents: Vec<(u32, Document)>) -> Vec<Vec<(u32, Document)>> {
+    // 16 bytes covers the node index and the Candid tags of a document.
+    upload_batches(documents, |document| {
+        16 + match &document.id {
+            DocumentId::Text(id) => id.len(),
+            DocumentId::Nat64(_) => 8,
+        } + document.payload.as_ref().map_or(0, |payload| payload.len())
+    })
+}
+
+/// Splits per-node values into batches which fit into one upload request.
+fn upload_batches<T>(values: Vec<(u32, T)>, byte_size: impl Fn(&T) -> usize) -> Vec<Vec<(u32, T)>> {
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut batch_byte_size = 0;
+    for (node_index, value) in values {
+        let value_byte_size = byte_size(&value);
+
+        if !batch.is_empty() && batch_byte_size + value_byte_size > UPLOAD_BATCH_BYTE_SIZE {
+            batches.push(std::mem::take(&mut batch));
+            batch_byte_size = 0;
+        }
+        batch_byte_size += value_byte_size;
+        batch.push((node_index, value));
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+#[derive(CandidType, Deserialize)]
+enum AttributeValue {
+    Text(String),
+    Number(f64),
+}
+
+#[derive(CandidType, Deserialize)]
+struct Attributes(Vec<(String, AttributeValue)>);
+
+fn read_attributes(path: &str) -> Result<Vec<(u32, Attributes)>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(node_index, line)| {
+            let attributes = line
+                .split('\t')
+                .map(|pair| {
+                    let (key, value) = pair
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("line {}: `{pair}` is not `<key>=<value>`", node_index + 1))?;
+                    let value = match value.parse::<f64>() {
+                        Ok(number) => AttributeValue::Number(number),
+                        Err(_) => AttributeValue::Text(value.to_string()),
+                    };
+                    Ok((key.to_string(), value))
+                })
+                .collect::<Result<_>>()?;
+            Ok((node_index as u32, Attributes(attributes)))
+        })
+        .collect()
+}
+
+fn attribute_batches(attributes: Vec<(u32, Attributes)>) -> Vec<Vec<(u32, Attributes)>> {
+    // 16 bytes covers the node index and the Candid tags of every attribute.
+    upload_batches(attributes, |Attributes(attributes)| {
+        attributes.iter().map(|(key, value)| {
+            16 + key.len() + match value {
+                AttributeValue::Text(text) => text.len(),
+                AttributeValue::Number(_) => 8,
+            }
+        }).sum()
+    })
+}
+
+async fn call_upload_documents(
+    agent: &Agent,
+    target_canister_id: Principal,
+    documents: &[(u32, Document)],
+) -> Result<()> {
+    let method_name = "upload_documents";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&documents)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
     Ok(())
 }
 
-async fn get_missing_chunks(
+#[derive(CandidType, Deserialize)]
+struct IndexDigest {
+    chunk_byte_size: u64,
+    digest: Vec<u8>,
+}
+
+async fn call_index_digest(
     agent: &Agent,
     target_canister_id: Principal,
-) -> Result<BitVec<u8, Lsb0>> {
-
-    let mut data = Vec::new();
-    let mut index = 0;
-    while let Some(bytes) = call_missing_chunks(agent, target_canister_id, index).await? {
-        println!("fetch");
-        data.extend(bytes);
-        index += 1;
-    }
+) -> Result<IndexDigest> {
+    let method_name = "index_digest";
+    let response = agent.query(&target_canister_id, method_name).with_arg(Encode!()?).call().await?;
+    let index_digest = Decode!(&response, Result<IndexDigest, VectuneError>)??;
+
+    Ok(index_digest)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+async fn call_reset(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<()> {
+    let method_name = "reset";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!()?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+/// As many vectors as `insert` accepts in one call.
+const INSERT_BATCH_SIZE: usize = 100;
+
+async fn call_insert(
+    agent: &Agent,
+    target_canister_id: Principal,
+    vectors: &[Vec<f32>],
+) -> Result<Vec<u32>> {
+    let method_name = "insert";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&vectors)?)
+        .call_and_wait()
+        .await?;
+    let node_indices = Decode!(&response, Result<Vec<u32>, VectuneError>)??;
+
+    Ok(node_indices)
+}
+
+async fn call_merge(
+    agent: &Agent,
+    target_canister_id: Principal,
+    cancel: bool,
+) -> Result<()> {
+    let method_name = if cancel { "cancel_merge" } else { "merge" };
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!()?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_delete(
+    agent: &Agent,
+    target_canister_id: Principal,
+    node_indices: &[u32],
+    undelete: bool,
+) -> Result<()> {
+    let method_name = if undelete { "undelete" } else { "delete" };
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&node_indices)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_set_access(
+    agent: &Agent,
+    target_canister_id: Principal,
+    policy: AccessPolicy,
+    quota: Option<Quota>,
+) -> Result<()> {
+    let method_name = "set_access";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&policy, &quota)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_add_searcher(
+    agent: &Agent,
+    target_canister_id: Principal,
+    principal: Principal,
+    quota: Option<Quota>,
+) -> Result<()> {
+    let method_name = "add_searcher";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&principal, &quota)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_remove_searcher(
+    agent: &Agent,
+    target_canister_id: Principal,
+    principal: Principal,
+) -> Result<()> {
+    let method_name = "remove_searcher";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&principal)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_access(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<(Access, Vec<(Principal, Searcher)>)> {
+    let method_name = "access";
+    let response = agent
+        .query(&target_canister_id, method_name)
+        .with_arg(Encode!()?)
+        .call()
+        .await?;
+    let access = Decode!(&response, Result<(Access, Vec<(Principal, Searcher)>), VectuneError>)??;
+
+    Ok(access)
+}
+
+async fn call_add_admin(
+    agent: &Agent,
+    target_canister_id: Principal,
+    principal: Principal,
+    role: Role,
+) -> Result<()> {
+    let method_name = "add_admin";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&principal, &role)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_remove_admin(
+    agent: &Agent,
+    target_canister_id: Principal,
+    principal: Principal,
+) -> Result<()> {
+    let method_name = "remove_admin";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&principal)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_admins(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<Vec<(Principal, Role)>> {
+    let method_name = "admins";
+    let response = agent
+        .query(&target_canister_id, method_name)
+        .with_arg(Encode!()?)
+        .call()
+        .await?;
+    let admins = Decode!(&response, Result<Vec<(Principal, Role)>, VectuneError>)??;
+
+    Ok(admins)
+}
+
+async fn call_upload_chunk_hashes(
+    agent: &Agent,
+    target_canister_id: Principal,
+    first_chunk_index: u64,
+    chunk_hashes: &[u8],
+) -> Result<()> {
+    let method_name = "upload_chunk_hashes";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&first_chunk_index, &chunk_hashes)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_upload_header(
+    agent: &Agent,
+    target_canister_id: Principal,
+    header: &[u8],
+) -> Result<()> {
+    let method_name = "upload_header";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&header)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_start(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<()> {
+    let method_name = "start";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!()?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+async fn call_promote(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<()> {
+    let method_name = "promote";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!()?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+fn confirm(message: &str) -> Result<bool> {
+    print!("{message} [y/N] ");
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+async fn call_upload_attributes(
+    agent: &Agent,
+    target_canister_id: Principal,
+    attributes: &[(u32, Attributes)],
+) -> Result<()> {
+    let method_name = "upload_attributes";
+    let response = agent
+        .update(&target_canister_id, method_name)
+        .with_arg(Encode!(&attributes)?)
+        .call_and_wait()
+        .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
+    Ok(())
+}
+
+#[derive(CandidType, Deserialize)]
+struct MissingChunkRanges {
+    ranges: Vec<(u64, u64)>,
+    next_cursor: Option<u64>,
+    num_uploaded_chunks: u64,
+    num_chunks: u64,
+}
 
-    let uploaded_chunks: BitVec<u8, Lsb0> = bincode::deserialize(&data).unwrap();
+/// Returns the `[start, end)` ranges of every missing chunk, and the number of chunks.
+async fn get_missing_chunk_ranges(
+    agent: &Agent,
+    target_canister_id: Principal,
+) -> Result<(Vec<(u64, u64)>, u64)> {
 
-    Ok(uploaded_chunks)
+    let mut ranges = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let response = call_missing_chunk_ranges(agent, target_canister_id, cursor).await?;
+        println!("fetch, uploaded: {}/{}", response.num_uploaded_chunks, response.num_chunks);
+        ranges.extend(response.ranges);
+
+        match response.next_cursor {
+            Some(next_cursor) => cursor = next_cursor,
+            None => return Ok((ranges, response.num_chunks)),
+        }
+    }
 }
 
-async fn call_missing_chunks(
+async fn call_missing_chunk_ranges(
     agent: &Agent,
     target_canister_id: Principal,
-    index: u64,
-) -> Result<Option<Vec<u8>>> {
-    let method_name = "missing_chunks";
-    let response = agent.query(&target_canister_id, method_name).with_arg(Encode!(&index)?).call().await?;
-    let Some(uploaded_chunks) = Decode!(&response, Option<Vec<u8>>)?  else {return Ok(None)};
+    cursor: u64,
+) -> Result<MissingChunkRanges> {
+    let method_name = "missing_chunk_ranges";
+    let limit: u64 = 10_000;
+    let response = agent
+        .query(&target_canister_id, method_name)
+        .with_arg(Encode!(&cursor, &limit)?)
+        .call()
+        .await?;
+    let missing_chunk_ranges = Decode!(&response, Result<MissingChunkRanges, VectuneError>)??;
 
-    Ok(Some(uploaded_chunks))
+    Ok(missing_chunk_ranges)
 }
 
-async fn call_upload_chunk(
+async fn call_upload_chunks(
     agent: &Agent,
     target_canister_id: Principal,
-    arg: (Vec<u8>, u64),
+    chunks: &[(u64, Vec<u8>)],
 ) -> Result<()> {
-    let method_name = "upload_chunk";
-    let (chunk, index) = arg;
-    let _ = agent
+    let method_name = "upload_chunks";
+    let response = agent
         .update(&target_canister_id, method_name)
-        .with_arg(Encode!(&chunk, &index)?)
+        .with_arg(Encode!(&chunks)?)
         .call_and_wait()
         .await?;
+    Decode!(&response, Result<(), VectuneError>)??;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decoded by `GraphHeader::decode` in the tests of `header.rs` of the canister.
+    const CANISTER_HEADER: [u8; 112] = [
+        0x56, 0x45, 0x43, 0x54, 0x55, 0x4e, 0x45, 0x00, 0x01, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00,
+        0x46, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+        0xe9, 0x43, 0xea, 0x56, 0xae, 0x49, 0x1b, 0x6a, 0x55, 0xeb, 0xcc, 0xc8, 0x04, 0x81, 0x2d, 0xa4,
+        0xba, 0xf5, 0xa4, 0x16, 0xad, 0x7d, 0xc5, 0xe1, 0x3c, 0x41, 0x9c, 0x94, 0xf6, 0x91, 0xc8, 0x26,
+    ];
+
+    #[test]
+    fn graph_header_is_what_the_canister_decodes() {
+        let layout = GraphLayout {
+            medoid_node_index: 42,
+            sector_byte_size: 4096,
+            num_vectors: 1000,
+            vector_dim: 96,
+            edge_degrees: 70,
+        };
+        let index_digest: Vec<u8> = (0..32).collect();
+        assert_eq!(graph_header(&layout, Metric::Cosine, &index_digest), CANISTER_HEADER);
+    }
+
+    #[test]
+    fn layout_check_expects_the_exact_graph_size() {
+        // 6 nodes of (96 + 70 + 1) * 4 = 668 bytes fit into a sector.
+        let layout = GraphLayout {
+            medoid_node_index: 0,
+            sector_byte_size: 4096,
+            num_vectors: 1000,
+            vector_dim: 96,
+            edge_degrees: 70,
+        };
+        assert!(layout.check(167 * 4096).is_ok());
+        assert!(layout.check(166 * 4096).is_err());
+        assert!(layout.check(168 * 4096).is_err());
+        assert!(layout.check(1000 * 668).is_err());
+
+        let layout = GraphLayout { vector_dim: 1024, ..layout };
+        assert!(layout.check(1000 * 4096).is_err());
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::{Deserialize, Serialize};
+use vectune::PointInterface;
+
+use crate::groundtruth::dot;
+
+// `PointInterface::dim` has no receiver, so the dimension of the vectors being built is kept here.
+// Not a thread local like in the canister, because `vectune::Builder` may build on several threads.
+static DIM: AtomicU32 = AtomicU32::new(0);
+
+/// A vector compared by `1 - cosine similarity`, the distance `Metric::Cosine` searches with in the
+/// canister. `ssd_vectune::point::Point` only knows the Euclidean distance.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CosinePoint(Vec<f32>);
+
+impl CosinePoint {
+    /// Sets the dimension returned by `PointInterface::dim` and used by `zero`.
+    /// Has to be called with the dimension of the vectors before building.
+    pub fn set_dim(dim: u32) {
+        DIM.store(dim, Ordering::Relaxed);
+    }
+}
+
+impl PointInterface for CosinePoint {
+    fn distance(&self, other: &Self) -> f32 {
+        assert_eq!(self.0.len(), other.0.len());
+
+        // Like the canister, a zero vector is as far from every vector as an orthogonal one.
+        let norms = (dot(&self.0, &self.0) * dot(&other.0, &other.0)).sqrt();
+        if norms == 0.0 {
+            1.0
+        } else {
+            1.0 - dot(&self.0, &other.0) / norms
+        }
+    }
+
+    fn dim() -> u32 {
+        DIM.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        CosinePoint(self.0.iter().zip(&other.0).map(|(x, y)| x + y).collect())
+    }
+
+    fn div(&self, divisor: &usize) -> Self {
+        CosinePoint(self.0.iter().map(|x| x / *divisor as f32).collect())
+    }
+
+    fn zero() -> Self {
+        CosinePoint(vec![0.0; Self::dim() as usize])
+    }
+
+    fn to_f32_vec(&self) -> Vec<f32> {
+        self.0.clone()
+    }
+
+    fn from_f32_vec(a: Vec<f32>) -> Self {
+        CosinePoint(a)
+    }
+}
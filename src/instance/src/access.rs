@@ -0,0 +1,270 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ops::Bound as RangeBound;
+
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{BTreeMap as StableBTreeMap, Cell as StableCell, DefaultMemoryImpl, Storable};
+
+use crate::error::{VectuneError, VectuneResult};
+use crate::MEMORY_MANAGER;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Entries of `USAGE` looked at by every counted search, see `expire_usage`.
+const USAGE_EXPIRY_BATCH_SIZE: usize = 8;
+
+thread_local! {
+    static ACCESS: RefCell<StableCell<Access, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            Access { policy: AccessPolicy::Public, quota: None }
+        ).unwrap()
+    );
+    static SEARCHERS: RefCell<StableBTreeMap<Principal, Searcher, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))))
+    );
+    static USAGE: RefCell<StableBTreeMap<Principal, Usage, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))))
+    );
+    // Where `expire_usage` goes on from. Lost on upgrade, which only restarts the sweep.
+    static USAGE_EXPIRY_CURSOR: RefCell<Option<Principal>> = const { RefCell::new(None) };
+}
+
+/// Who may call the search endpoints. Admins can always search.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum AccessPolicy {
+    /// Anyone may search, including the anonymous principal.
+    Public,
+    /// Only the principals added with `add_searcher` may search.
+    AllowList,
+}
+
+/// At most `max_searches` searches per principal in every window of `window_seconds`.
+///
+/// A query call can not persist anything, so searches under a quota have to go through the
+/// `_update` search endpoints to be counted. Anonymous callers all share one quota.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub struct Quota {
+    pub max_searches: u64,
+    pub window_seconds: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Access {
+    pub policy: AccessPolicy,
+    /// Applies to every caller without a quota of its own.
+    pub quota: Option<Quota>,
+}
+
+impl Storable for Access {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A principal added with `add_searcher`. Its `quota` replaces the default one of `Access`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Searcher {
+    pub quota: Option<Quota>,
+}
+
+impl Storable for Searcher {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Searches of a principal in the current window.
+struct Usage {
+    window_end: u64, // nanoseconds since the epoch
+    num_searches: u64,
+}
+
+impl Storable for Usage {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = self.window_end.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.num_searches.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            window_end: u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+            num_searches: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 16, is_fixed_size: true };
+}
+
+pub fn access() -> Access {
+    ACCESS.with(|access| access.borrow().get().clone())
+}
+
+pub fn set_access(access: Access) -> VectuneResult<()> {
+    if let Some(quota) = &access.quota {
+        check_quota(quota)?;
+    }
+    ACCESS.with(|cell| {
+        let _ = cell.borrow_mut().set(access);
+    });
+    Ok(())
+}
+
+pub fn searchers() -> Vec<(Principal, Searcher)> {
+    SEARCHERS.with(|searchers| searchers.borrow().iter().collect())
+}
+
+pub fn add_searcher(principal: Principal, searcher: Searcher) -> VectuneResult<()> {
+    if let Some(quota) = &searcher.quota {
+        check_quota(quota)?;
+    }
+    SEARCHERS.with(|searchers| searchers.borrow_mut().insert(principal, searcher));
+    Ok(())
+}
+
+pub fn remove_searcher(principal: &Principal) {
+    SEARCHERS.with(|searchers| searchers.borrow_mut().remove(principal));
+    USAGE.with(|usage| usage.borrow_mut().remove(principal));
+}
+
+fn check_quota(quota: &Quota) -> VectuneResult<()> {
+    if quota.window_seconds == 0 {
+        return Err(VectuneError::InvalidArgument("window_seconds must be at least 1".to_string()));
+    }
+    Ok(())
+}
+
+/// The quota the searches of the caller count against, `None` if it may search freely.
+fn search_quota(caller: &Principal) -> VectuneResult<Option<Quota>> {
+    if crate::assert_owner().is_ok() {
+        return Ok(None);
+    }
+
+    let access = access();
+    let searcher = SEARCHERS.with(|searchers| searchers.borrow().get(caller));
+
+    match (access.policy, searcher) {
+        (_, Some(searcher)) => Ok(searcher.quota.or(access.quota)),
+        (AccessPolicy::Public, None) => Ok(access.quota),
+        (AccessPolicy::AllowList, None) => Err(VectuneError::SearchNotAllowed),
+    }
+}
+
+/// Checks that the caller may search from a query endpoint. Changes made by a query endpoint are
+/// discarded even when it is called as an update, so callers under a quota are refused there.
+pub fn check_search_access() -> VectuneResult<()> {
+    match search_quota(&ic_cdk::caller())? {
+        Some(_) => Err(VectuneError::ReplicatedCallRequired),
+        None => Ok(()),
+    }
+}
+
+/// Checks that the caller may run `num_searches` searches from an update endpoint, and counts
+/// them against its quota.
+pub fn count_searches(num_searches: u64) -> VectuneResult<()> {
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    expire_usage(now);
+
+    let Some(quota) = search_quota(&caller)? else {
+        return Ok(());
+    };
+
+    USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut current = match usage.get(&caller) {
+            Some(current) if now < current.window_end => current,
+            _ => Usage {
+                window_end: now.saturating_add(quota.window_seconds.saturating_mul(NANOS_PER_SECOND)),
+                num_searches: 0,
+            },
+        };
+
+        if current.num_searches.saturating_add(num_searches) > quota.max_searches {
+            return Err(VectuneError::QuotaExceeded {
+                retry_after_seconds: (current.window_end - now).div_ceil(NANOS_PER_SECOND),
+            });
+        }
+
+        current.num_searches += num_searches;
+        usage.insert(caller, current);
+        Ok(())
+    })
+}
+
+/// Removes the entries of `USAGE` whose window has ended among the next few after the ones the
+/// last call looked at, so that callers which stopped searching do not stay in there forever.
+fn expire_usage(now: u64) {
+    USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let cursor = USAGE_EXPIRY_CURSOR.with(|cursor| *cursor.borrow());
+        let entries: Vec<(Principal, Usage)> = match cursor {
+            Some(cursor) => usage
+                .range((RangeBound::Excluded(cursor), RangeBound::Unbounded))
+                .take(USAGE_EXPIRY_BATCH_SIZE)
+                .collect(),
+            None => usage.iter().take(USAGE_EXPIRY_BATCH_SIZE).collect(),
+        };
+
+        for (principal, entry) in &entries {
+            if now >= entry.window_end {
+                usage.remove(principal);
+            }
+        }
+
+        // Starts over from the first entry once the last one was looked at.
+        let next_cursor = match entries.last() {
+            Some((principal, _)) if entries.len() == USAGE_EXPIRY_BATCH_SIZE => Some(*principal),
+            _ => None,
+        };
+        USAGE_EXPIRY_CURSOR.with(|cursor| *cursor.borrow_mut() = next_cursor);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(id: u8) -> Principal {
+        Principal::from_slice(&[id])
+    }
+
+    #[test]
+    fn expire_usage_removes_ended_windows_batch_by_batch() {
+        let num_entries = USAGE_EXPIRY_BATCH_SIZE as u8 * 2 + 1;
+        USAGE.with(|usage| {
+            let mut usage = usage.borrow_mut();
+            for id in 0..num_entries {
+                // Every even entry's window has ended at time 100.
+                let window_end = if id % 2 == 0 { 100 } else { 200 };
+                usage.insert(principal(id), Usage { window_end, num_searches: 1 });
+            }
+        });
+
+        expire_usage(100);
+        let remaining = USAGE.with(|usage| usage.borrow().len());
+        assert_eq!(remaining, num_entries as u64 - USAGE_EXPIRY_BATCH_SIZE as u64 / 2);
+
+        expire_usage(100);
+        expire_usage(100);
+        USAGE.with(|usage| {
+            let usage = usage.borrow();
+            assert_eq!(usage.len(), num_entries as u64 / 2);
+            assert!(usage.iter().all(|(_, entry)| entry.window_end == 200));
+        });
+    }
+}
@@ -0,0 +1,290 @@
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::{DefaultMemoryImpl, Memory};
+
+use crate::error::VectuneResult;
+use crate::grow_memory;
+
+// The header holds the number of bits and the number of set bits, as little endian u64.
+const LEN_OFFSET: u64 = 0;
+const COUNT_ONES_OFFSET: u64 = 8;
+const HEADER_BYTE_SIZE: u64 = 16;
+const BLOCK_BYTE_SIZE: u64 = 4096;
+
+/// A fixed length bitmap living in its own memory, in `Lsb0` order.
+///
+/// Setting or clearing a bit touches one byte and the counter of set bits, so `set`, `unset`
+/// and `count_ones` are O(1) regardless of the length.
+pub struct StableBitmap {
+    memory: VirtualMemory<DefaultMemoryImpl>,
+}
+
+impl StableBitmap {
+    /// Creates a bitmap of `len` zero bits, overwriting whatever the memory held before.
+    pub fn new(memory: VirtualMemory<DefaultMemoryImpl>, len: u64) -> VectuneResult<Self> {
+        let byte_size = len.div_ceil(8);
+        grow_memory(&memory, HEADER_BYTE_SIZE + byte_size)?;
+
+        let bitmap = Self { memory };
+        bitmap.write_zeros(0, byte_size);
+        bitmap.write_u64(LEN_OFFSET, len);
+        bitmap.write_u64(COUNT_ONES_OFFSET, 0);
+        Ok(bitmap)
+    }
+
+    /// Creates a copy of `source`, overwriting whatever the memory held before.
+    pub fn copy(memory: VirtualMemory<DefaultMemoryImpl>, source: &StableBitmap) -> VectuneResult<Self> {
+        let total_byte_size = HEADER_BYTE_SIZE + source.num_bits().div_ceil(8);
+        grow_memory(&memory, total_byte_size)?;
+
+        let mut block = vec![0; BLOCK_BYTE_SIZE as usize];
+        let mut offset = 0;
+        while offset < total_byte_size {
+            let block_len = std::cmp::min(BLOCK_BYTE_SIZE, total_byte_size - offset) as usize;
+            source.memory.read(offset, &mut block[..block_len]);
+            memory.write(offset, &block[..block_len]);
+            offset += block_len as u64;
+        }
+
+        Ok(Self { memory })
+    }
+
+    /// Opens a bitmap created by `new`.
+    pub fn init(memory: VirtualMemory<DefaultMemoryImpl>) -> Self {
+        Self { memory }
+    }
+
+    /// Lengthens the bitmap to `len` bits, the new ones zero. A shorter `len` is a no-op.
+    pub fn grow(&self, len: u64) -> VectuneResult<()> {
+        let current_len = self.num_bits();
+        if len <= current_len {
+            return Ok(());
+        }
+
+        // Bits past the length are always zero, so only the bytes after the current last one
+        // have to be cleared.
+        let current_byte_size = current_len.div_ceil(8);
+        let byte_size = len.div_ceil(8);
+        grow_memory(&self.memory, HEADER_BYTE_SIZE + byte_size)?;
+        self.write_zeros(current_byte_size, byte_size);
+        self.write_u64(LEN_OFFSET, len);
+        Ok(())
+    }
+
+    pub fn num_bits(&self) -> u64 {
+        self.read_u64(LEN_OFFSET)
+    }
+
+    pub fn count_ones(&self) -> u64 {
+        self.read_u64(COUNT_ONES_OFFSET)
+    }
+
+    pub fn count_zeros(&self) -> u64 {
+        self.num_bits() - self.count_ones()
+    }
+
+    pub fn get(&self, index: u64) -> bool {
+        assert!(index < self.num_bits());
+        let byte = self.read_byte(index / 8);
+        byte & (1 << (index % 8)) != 0
+    }
+
+    /// Sets the bit at `index` and returns whether it was zero before.
+    pub fn set(&self, index: u64) -> bool {
+        assert!(index < self.num_bits());
+        let byte = self.read_byte(index / 8);
+        let mask = 1 << (index % 8);
+        if byte & mask != 0 {
+            return false;
+        }
+
+        self.memory.write(HEADER_BYTE_SIZE + index / 8, &[byte | mask]);
+        self.write_u64(COUNT_ONES_OFFSET, self.count_ones() + 1);
+        true
+    }
+
+    /// Clears the bit at `index` and returns whether it was one before.
+    pub fn unset(&self, index: u64) -> bool {
+        assert!(index < self.num_bits());
+        let byte = self.read_byte(index / 8);
+        let mask = 1 << (index % 8);
+        if byte & mask == 0 {
+            return false;
+        }
+
+        self.memory.write(HEADER_BYTE_SIZE + index / 8, &[byte & !mask]);
+        self.write_u64(COUNT_ONES_OFFSET, self.count_ones() - 1);
+        true
+    }
+
+    /// The index of the first bit equal to `value` at or after `from`.
+    pub fn next(&self, from: u64, value: bool) -> Option<u64> {
+        let len = self.num_bits();
+        let byte_size = len.div_ceil(8);
+        // Bytes in which no bit can match.
+        let skip = if value { 0x00 } else { 0xFF };
+
+        let mut block = vec![0; BLOCK_BYTE_SIZE as usize];
+        let mut byte_index = from / 8;
+        while byte_index < byte_size {
+            let block_len = std::cmp::min(BLOCK_BYTE_SIZE, byte_size - byte_index) as usize;
+            self.memory.read(HEADER_BYTE_SIZE + byte_index, &mut block[..block_len]);
+
+            for (i, byte) in block[..block_len].iter().enumerate() {
+                if *byte == skip {
+                    continue;
+                }
+                let first_bit_index = (byte_index + i as u64) * 8;
+                for bit in 0..8 {
+                    let bit_index = first_bit_index + bit;
+                    if bit_index >= from && bit_index < len && ((byte >> bit) & 1 == 1) == value {
+                        return Some(bit_index);
+                    }
+                }
+            }
+
+            byte_index += block_len as u64;
+        }
+
+        None
+    }
+
+    fn write_zeros(&self, from_byte_index: u64, to_byte_index: u64) {
+        let zeros = vec![0; BLOCK_BYTE_SIZE as usize];
+        let mut byte_index = from_byte_index;
+        while byte_index < to_byte_index {
+            let block_len = std::cmp::min(BLOCK_BYTE_SIZE, to_byte_index - byte_index);
+            self.memory.write(HEADER_BYTE_SIZE + byte_index, &zeros[..block_len as usize]);
+            byte_index += block_len;
+        }
+    }
+
+    fn read_byte(&self, byte_index: u64) -> u8 {
+        let mut byte = [0];
+        self.memory.read(HEADER_BYTE_SIZE + byte_index, &mut byte);
+        byte[0]
+    }
+
+    fn read_u64(&self, offset: u64) -> u64 {
+        let mut bytes = [0; 8];
+        self.memory.read(offset, &mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.memory.write(offset, &value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::memory_manager::{MemoryId, MemoryManager};
+
+    use super::*;
+
+    const BLOCK_BIT_SIZE: u64 = BLOCK_BYTE_SIZE * 8;
+
+    fn memories() -> (VirtualMemory<DefaultMemoryImpl>, VirtualMemory<DefaultMemoryImpl>) {
+        let manager = MemoryManager::init(DefaultMemoryImpl::default());
+        (manager.get(MemoryId::new(0)), manager.get(MemoryId::new(1)))
+    }
+
+    #[test]
+    fn next_finds_ones_across_byte_and_block_boundaries() {
+        let len = 3 * BLOCK_BIT_SIZE + 5;
+        let bitmap = StableBitmap::new(memories().0, len).unwrap();
+        assert_eq!(bitmap.next(0, true), None);
+
+        bitmap.set(BLOCK_BIT_SIZE);
+        bitmap.set(len - 1);
+        assert_eq!(bitmap.next(0, true), Some(BLOCK_BIT_SIZE));
+        assert_eq!(bitmap.next(BLOCK_BIT_SIZE + 1, true), Some(len - 1));
+
+        bitmap.set(7);
+        bitmap.set(8);
+        bitmap.set(BLOCK_BIT_SIZE - 1);
+        assert_eq!(bitmap.next(0, true), Some(7));
+        assert_eq!(bitmap.next(7, true), Some(7));
+        assert_eq!(bitmap.next(8, true), Some(8));
+        assert_eq!(bitmap.next(9, true), Some(BLOCK_BIT_SIZE - 1));
+        assert_eq!(bitmap.next(len - 1, true), Some(len - 1));
+        assert_eq!(bitmap.next(len, true), None);
+    }
+
+    #[test]
+    fn next_finds_zeros_across_byte_and_block_boundaries() {
+        let len = 2 * BLOCK_BIT_SIZE;
+        let bitmap = StableBitmap::new(memories().0, len).unwrap();
+        for index in 0..16 {
+            bitmap.set(index);
+        }
+        assert_eq!(bitmap.next(0, false), Some(16));
+
+        for index in 16..BLOCK_BIT_SIZE + 1 {
+            bitmap.set(index);
+        }
+        assert_eq!(bitmap.next(0, false), Some(BLOCK_BIT_SIZE + 1));
+        assert_eq!(bitmap.next(BLOCK_BIT_SIZE + 2, false), Some(BLOCK_BIT_SIZE + 2));
+    }
+
+    #[test]
+    fn next_ignores_the_padding_of_the_last_byte() {
+        let bitmap = StableBitmap::new(memories().0, 13).unwrap();
+        for index in 0..13 {
+            bitmap.set(index);
+        }
+        assert_eq!(bitmap.next(0, false), None);
+        assert_eq!(bitmap.count_zeros(), 0);
+    }
+
+    #[test]
+    fn set_and_unset_keep_the_count_of_ones() {
+        let bitmap = StableBitmap::new(memories().0, 100).unwrap();
+        assert_eq!((bitmap.count_ones(), bitmap.count_zeros()), (0, 100));
+
+        assert!(bitmap.set(3));
+        assert!(!bitmap.set(3));
+        assert!(bitmap.set(99));
+        assert_eq!((bitmap.count_ones(), bitmap.count_zeros()), (2, 98));
+        assert!(bitmap.get(3) && bitmap.get(99) && !bitmap.get(4));
+
+        assert!(bitmap.unset(3));
+        assert!(!bitmap.unset(3));
+        assert!(!bitmap.unset(50));
+        assert_eq!((bitmap.count_ones(), bitmap.count_zeros()), (1, 99));
+        assert!(!bitmap.get(3));
+    }
+
+    #[test]
+    fn grow_keeps_the_bits_and_adds_zeros() {
+        let bitmap = StableBitmap::new(memories().0, 10).unwrap();
+        bitmap.set(2);
+        bitmap.set(9);
+
+        bitmap.grow(BLOCK_BIT_SIZE + 10).unwrap();
+        assert_eq!(bitmap.num_bits(), BLOCK_BIT_SIZE + 10);
+        assert_eq!(bitmap.count_ones(), 2);
+        assert!(bitmap.get(2) && bitmap.get(9));
+        assert_eq!(bitmap.next(10, true), None);
+
+        bitmap.grow(5).unwrap();
+        assert_eq!(bitmap.num_bits(), BLOCK_BIT_SIZE + 10);
+    }
+
+    #[test]
+    fn copy_is_independent_of_its_source() {
+        let (source_memory, copy_memory) = memories();
+        let source = StableBitmap::new(source_memory, BLOCK_BIT_SIZE * 8 + 3).unwrap();
+        source.set(1);
+        source.set(BLOCK_BIT_SIZE * 8 + 2);
+
+        let copy = StableBitmap::copy(copy_memory, &source).unwrap();
+        assert_eq!(copy.num_bits(), source.num_bits());
+        assert_eq!(copy.count_ones(), 2);
+        assert!(copy.get(1) && copy.get(BLOCK_BIT_SIZE * 8 + 2));
+
+        copy.unset(1);
+        copy.set(5);
+        assert!(source.get(1) && !source.get(5));
+        assert_eq!(source.count_ones(), 2);
+    }
+}
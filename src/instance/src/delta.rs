@@ -0,0 +1,132 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{BTreeMap as StableBTreeMap, DefaultMemoryImpl, Storable};
+use vectune::PointInterface;
+
+/// The delta index has to stay small enough to be searched next to the graph in one query call.
+pub const MAX_DELTA_NODES: u64 = 100_000;
+const EDGE_DEGREES: usize = 32;
+const BUILD_SIZE_L: usize = 64;
+pub(crate) const ALPHA: f32 = 1.2;
+/// Searches start from the first inserted node.
+const ENTRY_POINT: u32 = 0;
+
+/// A vector added with `insert`, keyed by its index in the delta index.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DeltaNode {
+    pub vector: Vec<f32>,
+    pub out_edges: Vec<u32>,
+}
+
+impl Storable for DeltaNode {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+pub type DeltaNodes = StableBTreeMap<u32, DeltaNode, VirtualMemory<DefaultMemoryImpl>>;
+
+/// Adds `vector` to the delta index and returns its index there, following the insert of
+/// FreshDiskANN: the nodes visited by a search for the vector become its candidate neighbors,
+/// and neighbors which get too many edges back are pruned again.
+pub fn insert<P: PointInterface>(nodes: &mut DeltaNodes, vector: Vec<f32>) -> u32 {
+    let node_index = nodes.len() as u32;
+    let point = P::from_f32_vec(vector.clone());
+
+    let (_, visited) = greedy_search(nodes, &point, BUILD_SIZE_L);
+    let out_edges = robust_prune(visited, node_index, EDGE_DEGREES, |out_edge| load_point::<P>(nodes, out_edge));
+    nodes.insert(node_index, DeltaNode { vector, out_edges: out_edges.clone() });
+
+    for neighbor in out_edges {
+        let mut node = nodes.get(&neighbor).unwrap();
+        node.out_edges.push(node_index);
+        if node.out_edges.len() > EDGE_DEGREES {
+            let neighbor_point = P::from_f32_vec(node.vector.clone());
+            let candidates = node
+                .out_edges
+                .iter()
+                .map(|&out_edge| (neighbor_point.distance(&load_point(nodes, out_edge)), out_edge))
+                .collect();
+            node.out_edges = robust_prune(candidates, neighbor, EDGE_DEGREES, |out_edge| load_point::<P>(nodes, out_edge));
+        }
+        nodes.insert(neighbor, node);
+    }
+
+    node_index
+}
+
+/// The `top_k` closest nodes of the delta index, closest first, as indices into the delta index.
+pub fn search<P: PointInterface>(nodes: &DeltaNodes, query_point: &P, top_k: usize, size_l: usize) -> Vec<(f32, u32)> {
+    let (mut k_ann, _) = greedy_search(nodes, query_point, size_l);
+    k_ann.truncate(top_k);
+    k_ann
+}
+
+/// Returns the `size_l` closest nodes found, and every node whose out-edges were followed.
+fn greedy_search<P: PointInterface>(nodes: &DeltaNodes, query_point: &P, size_l: usize) -> (Vec<(f32, u32)>, Vec<(f32, u32)>) {
+    if nodes.is_empty() {
+        return (vec![], vec![]);
+    }
+
+    let mut list = vec![(query_point.distance(&load_point(nodes, ENTRY_POINT)), ENTRY_POINT)];
+    let mut seen: HashSet<u32> = HashSet::from([ENTRY_POINT]);
+    let mut expanded: HashSet<u32> = HashSet::new();
+    let mut visited = vec![];
+
+    while let Some(&(distance, node_index)) = list.iter().find(|(_, node_index)| !expanded.contains(node_index)) {
+        expanded.insert(node_index);
+        visited.push((distance, node_index));
+
+        for out_edge in nodes.get(&node_index).unwrap().out_edges {
+            if !seen.insert(out_edge) {
+                continue;
+            }
+            let distance = query_point.distance(&load_point(nodes, out_edge));
+            let position = list.partition_point(|&(kept, _)| kept <= distance);
+            list.insert(position, (distance, out_edge));
+        }
+        list.truncate(size_l);
+    }
+
+    (list, visited)
+}
+
+/// Keeps the closest candidates, given with their distance to the node, skipping those already
+/// covered by a kept one that is `ALPHA` times closer to them than the node is. `load_point` reads
+/// the vector of a candidate.
+pub(crate) fn robust_prune<P: PointInterface>(
+    mut candidates: Vec<(f32, u32)>,
+    node_index: u32,
+    edge_degrees: usize,
+    load_point: impl Fn(u32) -> P,
+) -> Vec<u32> {
+    candidates.retain(|&(_, candidate)| candidate != node_index);
+    candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    candidates.dedup_by_key(|(_, candidate)| *candidate);
+
+    let mut out_edges: Vec<(u32, P)> = Vec::with_capacity(edge_degrees);
+    for (distance, candidate) in candidates {
+        if out_edges.len() == edge_degrees {
+            break;
+        }
+        let candidate_point = load_point(candidate);
+        if out_edges.iter().all(|(_, kept)| ALPHA * kept.distance(&candidate_point) > distance) {
+            out_edges.push((candidate, candidate_point));
+        }
+    }
+    out_edges.into_iter().map(|(out_edge, _)| out_edge).collect()
+}
+
+fn load_point<P: PointInterface>(nodes: &DeltaNodes, node_index: u32) -> P {
+    P::from_f32_vec(nodes.get(&node_index).unwrap().vector)
+}
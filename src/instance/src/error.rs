@@ -0,0 +1,51 @@
+use candid::{CandidType, Deserialize};
+
+use crate::simd_point::Metric;
+
+/// The lifecycle state of the index.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    None,
+    Loading,
+    Running,
+    Staging,
+}
+
+/// The error every endpoint of the canister returns instead of trapping.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum VectuneError {
+    /// The caller is neither a controller nor in the admin list with the role the endpoint needs.
+    NotOwner,
+    /// The endpoint can not be called in the current state of the index.
+    WrongState { expected: Vec<State>, actual: State },
+    InvalidArgument(String),
+    ChunkOutOfRange { chunk_index: u64, num_chunks: u64 },
+    ChunkHashMismatch { chunk_index: u64 },
+    /// The hash of the chunk has not been uploaded with `upload_chunk_hashes` yet.
+    ChunkHashMissing { chunk_index: u64 },
+    /// The chunk was uploaded before with different bytes.
+    ChunkConflict { chunk_index: u64 },
+    /// `start` or `promote` was called before every chunk was uploaded.
+    UploadIncomplete { missing_chunks: u64 },
+    /// The uploaded chunk hashes do not hash to the `index_digest` given to `initialize`.
+    DigestMismatch,
+    DimensionMismatch { expected: u64, actual: u64 },
+    /// The graph header is missing, corrupt, of an unsupported format version, or does not
+    /// describe the graph given to `initialize`.
+    InvalidHeader(String),
+    /// Stable memory could not be grown.
+    OutOfMemory,
+    /// The access policy is `AllowList` and the caller is not in it.
+    SearchNotAllowed,
+    /// The caller is under a quota, which only the `_update` search endpoints count.
+    ReplicatedCallRequired,
+    QuotaExceeded { retry_after_seconds: u64 },
+    /// `insert` would grow the delta index past the nodes it can hold.
+    DeltaIndexFull { max_nodes: u64 },
+    /// `insert` was called while `merge` empties the delta index.
+    MergeInProgress,
+    /// `insert` and `merge` can not link nodes of an index searched with this metric.
+    MetricNotSupported { metric: Metric },
+}
+
+pub type VectuneResult<T> = Result<T, VectuneError>;
@@ -0,0 +1,340 @@
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_stable_structures::memory_manager::VirtualMemory;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{BTreeMap as StableBTreeMap, DefaultMemoryImpl, Storable};
+use vectune::{GraphInterface, PointInterface};
+
+use crate::bitmap::StableBitmap;
+use crate::error::{VectuneError, VectuneResult};
+
+/// Filters can nest, but not without bound, since every visited node is matched against them.
+pub const MAX_FILTER_NODES: usize = 64;
+pub const MAX_LABEL_ENTRY_POINTS: usize = 10_000;
+
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    Number(f64),
+}
+
+/// The attributes of one node, such as `lang = Text("ja")` or `published_at = Number(1.7e9)`.
+/// A key appears at most once.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Attributes(pub Vec<(String, AttributeValue)>);
+
+impl Attributes {
+    fn get(&self, key: &str) -> Option<&AttributeValue> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, value)| value)
+    }
+}
+
+impl Storable for Attributes {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A predicate over the attributes of a node. Nodes without the key never match `Equals`, `In`
+/// or `Range`.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum Filter {
+    Equals { key: String, value: AttributeValue },
+    In { key: String, values: Vec<AttributeValue> },
+    /// Both bounds are inclusive, and only numbers are in range.
+    Range { key: String, min: Option<f64>, max: Option<f64> },
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn num_nodes(&self) -> usize {
+        match self {
+            Filter::Equals { .. } | Filter::In { .. } | Filter::Range { .. } => 1,
+            Filter::And(filters) | Filter::Or(filters) => 1 + filters.iter().map(Filter::num_nodes).sum::<usize>(),
+            Filter::Not(filter) => 1 + filter.num_nodes(),
+        }
+    }
+
+    pub fn matches(&self, attributes: &Attributes) -> bool {
+        match self {
+            Filter::Equals { key, value } => attributes.get(key) == Some(value),
+            Filter::In { key, values } => attributes.get(key).is_some_and(|value| values.contains(value)),
+            Filter::Range { key, min, max } => match attributes.get(key) {
+                Some(AttributeValue::Number(number)) => {
+                    min.is_none_or(|min| *number >= min) && max.is_none_or(|max| *number <= max)
+                }
+                _ => false,
+            },
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(attributes)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(attributes)),
+            Filter::Not(filter) => !filter.matches(attributes),
+        }
+    }
+
+    /// Labels of which every matching node carries at least one, if the filter implies any.
+    fn required_labels(&self) -> Option<Vec<(&str, &str)>> {
+        match self {
+            Filter::Equals { key, value: AttributeValue::Text(value) } => Some(vec![(key.as_str(), value.as_str())]),
+            Filter::In { key, values } => values
+                .iter()
+                .map(|value| match value {
+                    AttributeValue::Text(value) => Some((key.as_str(), value.as_str())),
+                    AttributeValue::Number(_) => None,
+                })
+                .collect(),
+            // Any of the conjuncts will do, the one with the fewest labels starts the search closest.
+            Filter::And(filters) => filters.iter().filter_map(Filter::required_labels).min_by_key(Vec::len),
+            Filter::Or(filters) => filters
+                .iter()
+                .map(Filter::required_labels)
+                .collect::<Option<Vec<_>>>()
+                .map(|labels| labels.concat()),
+            _ => None,
+        }
+    }
+}
+
+/// The node `search_filtered` starts from when a filter requires the text attribute `key` to be
+/// `value`, in the spirit of the per-label start points of Filtered-DiskANN.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct LabelEntryPoint {
+    pub key: String,
+    pub value: String,
+    pub node_index: u32,
+}
+
+pub fn check_label_entry_points(label_entry_points: &[LabelEntryPoint], num_vectors: u64) -> VectuneResult<()> {
+    if label_entry_points.len() > MAX_LABEL_ENTRY_POINTS {
+        return Err(VectuneError::InvalidArgument(format!("up to {MAX_LABEL_ENTRY_POINTS} label entry points are supported")));
+    }
+    if let Some(entry_point) = label_entry_points.iter().find(|entry_point| entry_point.node_index as u64 >= num_vectors) {
+        return Err(VectuneError::InvalidArgument(format!("label entry point {} is out of range", entry_point.node_index)));
+    }
+    Ok(())
+}
+
+/// The entry points of the labels `filter` requires, or the medoid if it requires none or some of
+/// them have no entry point.
+pub fn entry_points(filter: &Filter, label_entry_points: &[LabelEntryPoint], medoid_node_index: u32) -> Vec<u32> {
+    let entry_points: Option<Vec<u32>> = filter.required_labels().and_then(|labels| {
+        labels
+            .into_iter()
+            .map(|(key, value)| {
+                label_entry_points
+                    .iter()
+                    .find(|entry_point| entry_point.key == key && entry_point.value == value)
+                    .map(|entry_point| entry_point.node_index)
+            })
+            .collect()
+    });
+
+    match entry_points {
+        Some(entry_points) if !entry_points.is_empty() => entry_points,
+        _ => vec![medoid_node_index],
+    }
+}
+
+struct Candidate(f32, u32);
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// Best-first search which walks through every node, so that the graph stays connected, but only
+/// collects the ones matching `filter`.
+///
+/// `vectune::search` keeps the `size_l` closest nodes overall, so with a selective filter it can
+/// run out of candidates before finding `top_k` matches. Here `size_l` bounds the matches instead:
+/// the search goes on until the closest unexplored node is farther than the `size_l`-th closest
+/// match, or until `instruction_limit` is reached. Traversal starts from `entry_points`.
+///
+/// Nodes set in `tombstones` are walked through like the others, but never collected.
+#[allow(clippy::too_many_arguments)]
+pub fn filtered_search<P, G>(
+    graph: &mut G,
+    query_point: &P,
+    entry_points: &[u32],
+    top_k: usize,
+    size_l: usize,
+    filter: &Filter,
+    attributes: &StableBTreeMap<u32, Attributes, VirtualMemory<DefaultMemoryImpl>>,
+    tombstones: Option<&StableBitmap>,
+    instruction_limit: u64,
+) -> Vec<(f32, u32)>
+where
+    P: PointInterface,
+    G: GraphInterface<P>,
+{
+    let mut visited: HashSet<u32> = HashSet::new();
+    let mut frontier: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+    // The `size_l` closest matches, the farthest on top.
+    let mut matches: BinaryHeap<Candidate> = BinaryHeap::new();
+
+    let mut visit = |node_index: u32, graph: &mut G, frontier: &mut BinaryHeap<Reverse<Candidate>>, matches: &mut BinaryHeap<Candidate>| {
+        if !visited.insert(node_index) {
+            return;
+        }
+        let (point, _) = graph.get(&node_index);
+        let distance = query_point.distance(&point);
+        frontier.push(Reverse(Candidate(distance, node_index)));
+
+        let is_closer = matches.len() < size_l || matches.peek().is_some_and(|farthest| distance < farthest.0);
+        let is_deleted = tombstones.is_some_and(|tombstones| crate::is_deleted(tombstones, node_index));
+        if is_closer && !is_deleted && filter.matches(&attributes.get(&node_index).unwrap_or_default()) {
+            matches.push(Candidate(distance, node_index));
+            if matches.len() > size_l {
+                matches.pop();
+            }
+        }
+    };
+
+    for &entry_point in entry_points {
+        visit(entry_point, graph, &mut frontier, &mut matches);
+    }
+
+    while let Some(Reverse(Candidate(distance, node_index))) = frontier.pop() {
+        if matches.len() == size_l && matches.peek().is_some_and(|farthest| distance > farthest.0) {
+            break;
+        }
+        if ic_cdk::api::performance_counter(0) > instruction_limit {
+            break;
+        }
+
+        let (_, out_edges) = graph.get(&node_index);
+        for out_edge in out_edges {
+            visit(out_edge, graph, &mut frontier, &mut matches);
+        }
+    }
+
+    let mut k_ann: Vec<(f32, u32)> = matches.into_iter().map(|Candidate(distance, node_index)| (distance, node_index)).collect();
+    k_ann.sort_by(|a, b| a.0.total_cmp(&b.0));
+    k_ann.truncate(top_k);
+    k_ann
+}
+
+/// Checks a filter before it is run against every visited node.
+pub fn check_filter(filter: &Filter) -> VectuneResult<()> {
+    if filter.num_nodes() > MAX_FILTER_NODES {
+        return Err(VectuneError::InvalidArgument(format!("filters can have up to {MAX_FILTER_NODES} nodes")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MEDOID: u32 = 0;
+
+    fn text(value: &str) -> AttributeValue {
+        AttributeValue::Text(value.to_string())
+    }
+
+    fn equals(key: &str, value: &str) -> Filter {
+        Filter::Equals { key: key.to_string(), value: text(value) }
+    }
+
+    fn is_in(key: &str, values: &[&str]) -> Filter {
+        Filter::In { key: key.to_string(), values: values.iter().map(|value| text(value)).collect() }
+    }
+
+    fn range(key: &str, min: Option<f64>, max: Option<f64>) -> Filter {
+        Filter::Range { key: key.to_string(), min, max }
+    }
+
+    fn label_entry_points() -> Vec<LabelEntryPoint> {
+        [("lang", "ja", 10), ("lang", "en", 20), ("kind", "news", 30)]
+            .into_iter()
+            .map(|(key, value, node_index)| LabelEntryPoint { key: key.to_string(), value: value.to_string(), node_index })
+            .collect()
+    }
+
+    #[test]
+    fn matches_compares_values_and_combines_filters() {
+        let attributes = Attributes(vec![("lang".to_string(), text("ja")), ("published_at".to_string(), AttributeValue::Number(5.0))]);
+
+        assert!(equals("lang", "ja").matches(&attributes));
+        assert!(!equals("lang", "en").matches(&attributes));
+        assert!(!equals("kind", "ja").matches(&attributes));
+        assert!(is_in("lang", &["en", "ja"]).matches(&attributes));
+        assert!(!is_in("lang", &[]).matches(&attributes));
+
+        assert!(range("published_at", Some(5.0), Some(5.0)).matches(&attributes));
+        assert!(range("published_at", None, None).matches(&attributes));
+        assert!(!range("published_at", Some(5.5), None).matches(&attributes));
+        assert!(!range("published_at", None, Some(4.5)).matches(&attributes));
+        assert!(!range("lang", None, None).matches(&attributes));
+        assert!(!range("updated_at", None, None).matches(&attributes));
+
+        assert!(Filter::And(vec![equals("lang", "ja"), range("published_at", Some(1.0), None)]).matches(&attributes));
+        assert!(!Filter::And(vec![equals("lang", "ja"), equals("lang", "en")]).matches(&attributes));
+        assert!(Filter::Or(vec![equals("lang", "en"), equals("lang", "ja")]).matches(&attributes));
+        assert!(!Filter::Or(vec![]).matches(&attributes));
+        assert!(Filter::Not(Box::new(equals("lang", "en"))).matches(&attributes));
+    }
+
+    #[test]
+    fn entry_points_start_from_the_required_labels() {
+        let label_entry_points = label_entry_points();
+
+        assert_eq!(entry_points(&equals("lang", "ja"), &label_entry_points, MEDOID), vec![10]);
+        assert_eq!(entry_points(&is_in("lang", &["ja", "en"]), &label_entry_points, MEDOID), vec![10, 20]);
+        assert_eq!(
+            entry_points(&Filter::And(vec![equals("kind", "news"), is_in("lang", &["ja", "en"])]), &label_entry_points, MEDOID),
+            vec![30]
+        );
+        assert_eq!(
+            entry_points(&Filter::And(vec![range("published_at", None, None), equals("lang", "en")]), &label_entry_points, MEDOID),
+            vec![20]
+        );
+        assert_eq!(
+            entry_points(&Filter::Or(vec![equals("lang", "ja"), equals("kind", "news")]), &label_entry_points, MEDOID),
+            vec![10, 30]
+        );
+    }
+
+    #[test]
+    fn entry_points_fall_back_to_the_medoid() {
+        let label_entry_points = label_entry_points();
+        let filters = [
+            Filter::Or(vec![equals("lang", "ja"), range("published_at", None, None)]),
+            equals("lang", "fr"),
+            is_in("lang", &["ja", "fr"]),
+            Filter::Not(Box::new(equals("lang", "ja"))),
+            Filter::Equals { key: "lang".to_string(), value: AttributeValue::Number(1.0) },
+            is_in("lang", &[]),
+            Filter::And(vec![]),
+        ];
+
+        for filter in filters {
+            assert_eq!(entry_points(&filter, &label_entry_points, MEDOID), vec![MEDOID]);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use sha2::{Digest, Sha256};
+
+use crate::error::{VectuneError, VectuneResult};
+use crate::simd_point::Metric;
+
+/// Fixed little endian layout, written by `tool build` next to the graph:
+///
+/// | offset | size | field               |
+/// |--------|------|---------------------|
+/// | 0      | 8    | `MAGIC`             |
+/// | 8      | 4    | format version      |
+/// | 12     | 4    | vector dimension    |
+/// | 16     | 4    | edge degrees        |
+/// | 20     | 8    | sector byte size    |
+/// | 28     | 8    | node count          |
+/// | 36     | 4    | medoid node index   |
+/// | 40     | 1    | metric              |
+/// | 41     | 7    | zero                |
+/// | 48     | 32   | index digest        |
+/// | 80     | 32   | SHA-256 of 0..80    |
+pub const HEADER_BYTE_SIZE: usize = 112;
+pub const MAGIC: &[u8; 8] = b"VECTUNE\0";
+/// The ssd-vectune graph layout this wasm can search. Bumped whenever that layout changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+const CHECKSUM_OFFSET: usize = 80;
+
+/// What a graph file claims about itself, checked against the arguments of `initialize`.
+pub struct GraphHeader {
+    pub vector_dim: u64,
+    pub edge_degrees: u64,
+    pub sector_byte_size: u64,
+    pub num_vectors: u64,
+    pub medoid_node_index: u32,
+    pub metric: Metric,
+    pub index_digest: Vec<u8>,
+}
+
+impl GraphHeader {
+    pub fn decode(bytes: &[u8]) -> VectuneResult<Self> {
+        let invalid = |message: &str| VectuneError::InvalidHeader(message.to_string());
+
+        if bytes.len() != HEADER_BYTE_SIZE {
+            return Err(invalid("the header has the wrong size"));
+        }
+        if bytes[..8] != MAGIC[..] {
+            return Err(invalid("no graph header was uploaded"));
+        }
+        if Sha256::digest(&bytes[..CHECKSUM_OFFSET]).as_slice() != &bytes[CHECKSUM_OFFSET..] {
+            return Err(invalid("the header checksum does not match"));
+        }
+
+        let format_version = read_u32(bytes, 8);
+        if format_version != FORMAT_VERSION {
+            return Err(VectuneError::InvalidHeader(format!(
+                "the graph has format version {format_version}, but this wasm reads {FORMAT_VERSION}"
+            )));
+        }
+
+        let metric = match bytes[40] {
+            0 => Metric::Euclidean,
+            1 => Metric::Cosine,
+            2 => Metric::InnerProduct,
+            _ => return Err(invalid("the header has an unknown metric")),
+        };
+
+        Ok(Self {
+            vector_dim: read_u32(bytes, 12) as u64,
+            edge_degrees: read_u32(bytes, 16) as u64,
+            sector_byte_size: read_u64(bytes, 20),
+            num_vectors: read_u64(bytes, 28),
+            medoid_node_index: read_u32(bytes, 36),
+            metric,
+            index_digest: bytes[48..CHECKSUM_OFFSET].to_vec(),
+        })
+    }
+}
+
+/// The bytes `num_vectors` nodes take in the layout `tool build` writes: as many whole nodes as
+/// fit into a sector, and no node across two sectors.
+pub fn graph_byte_size(num_vectors: u64, vector_dim: u64, edge_degrees: u64, sector_byte_size: u64) -> VectuneResult<u64> {
+    let invalid = |message: &str| VectuneError::InvalidHeader(message.to_string());
+
+    let node_byte_size = vector_dim
+        .checked_add(edge_degrees)
+        .and_then(|num_values| num_values.checked_add(1))
+        .and_then(|num_values| num_values.checked_mul(4))
+        .ok_or_else(|| invalid("the nodes are too large"))?;
+    let nodes_per_sector = sector_byte_size / node_byte_size;
+    if nodes_per_sector == 0 {
+        return Err(VectuneError::InvalidHeader(format!(
+            "a node takes {node_byte_size} bytes, more than a sector of {sector_byte_size} bytes"
+        )));
+    }
+    num_vectors
+        .div_ceil(nodes_per_sector)
+        .checked_mul(sector_byte_size)
+        .ok_or_else(|| invalid("the graph is too large"))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Written by `graph_header` of the tool, whose tests expect the same bytes.
+    const TOOL_HEADER: [u8; HEADER_BYTE_SIZE] = [
+        0x56, 0x45, 0x43, 0x54, 0x55, 0x4e, 0x45, 0x00, 0x01, 0x00, 0x00, 0x00, 0x60, 0x00, 0x00, 0x00,
+        0x46, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+        0xe9, 0x43, 0xea, 0x56, 0xae, 0x49, 0x1b, 0x6a, 0x55, 0xeb, 0xcc, 0xc8, 0x04, 0x81, 0x2d, 0xa4,
+        0xba, 0xf5, 0xa4, 0x16, 0xad, 0x7d, 0xc5, 0xe1, 0x3c, 0x41, 0x9c, 0x94, 0xf6, 0x91, 0xc8, 0x26,
+    ];
+
+    #[test]
+    fn decodes_the_header_written_by_the_tool() {
+        let header = GraphHeader::decode(&TOOL_HEADER).unwrap();
+        assert_eq!(header.vector_dim, 96);
+        assert_eq!(header.edge_degrees, 70);
+        assert_eq!(header.sector_byte_size, 4096);
+        assert_eq!(header.num_vectors, 1000);
+        assert_eq!(header.medoid_node_index, 42);
+        assert_eq!(header.metric, Metric::Cosine);
+        assert_eq!(header.index_digest, (0..32).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn rejects_a_header_which_does_not_match_its_checksum() {
+        let mut bytes = TOOL_HEADER;
+        bytes[28] ^= 1;
+        assert!(matches!(GraphHeader::decode(&bytes), Err(VectuneError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn graph_byte_size_keeps_nodes_within_sectors() {
+        // (96 + 70 + 1) * 4 = 668 bytes per node, 6 of which fit into a sector.
+        assert_eq!(graph_byte_size(6, 96, 70, 4096).unwrap(), 4096);
+        assert_eq!(graph_byte_size(7, 96, 70, 4096).unwrap(), 2 * 4096);
+        assert_eq!(graph_byte_size(1000, 96, 70, 4096).unwrap(), 167 * 4096);
+        assert!(graph_byte_size(1, 1024, 70, 4096).is_err());
+    }
+}
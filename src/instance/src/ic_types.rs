@@ -1,3 +0,0 @@
-pub use ic_cdk::api::management_canister::main::canister_status;
-pub use ic_cdk::api::management_canister::main::CanisterIdRecord;
-pub use ic_cdk::api::management_canister::main::CanisterStatusResponse;
@@ -1,26 +1,40 @@
-pub mod ic_types;
+pub mod access;
+pub mod bitmap;
+pub mod delta;
+pub mod error;
+pub mod filter;
+pub mod header;
+pub mod merge;
 pub mod simd_point;
 
-use bitvec::prelude::*;
 use candid::Principal;
-use ic_cdk::{query, trap, update};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::storable::Bound;
-use ic_stable_structures::{Cell as StableCell, DefaultMemoryImpl, Storable};
+use ic_stable_structures::{BTreeMap as StableBTreeMap, Cell as StableCell, DefaultMemoryImpl, Storable};
 use ic_stable_structures::Memory;
 use candid::{CandidType, Decode, Deserialize, Encode};
 use ssd_vectune::graph::UnorderedGraph;
 use ssd_vectune::{graph_store::GraphStore, point::Point, storage::StorageTrait};
-use vectune::PointInterface;
+use vectune::{GraphInterface, PointInterface};
 use std::borrow::Cow;
 use std::cell::RefCell;
-use bytesize::MIB;
-
-use simd_point::Point as SIMDPoint;
+use std::time::Duration;
+use bytesize::KIB;
+use sha2::{Digest, Sha256};
+
+use access::{Access, AccessPolicy, Quota, Searcher};
+use bitmap::StableBitmap;
+use delta::{DeltaNodes, MAX_DELTA_NODES};
+use filter::{Attributes, Filter, LabelEntryPoint};
+use header::{GraphHeader, HEADER_BYTE_SIZE};
+use merge::MergeProgress;
+use error::{State, VectuneError, VectuneResult};
+use simd_point::{Metric, Point as SIMDPoint};
 
 /* Set custom random function */
 use rand::rngs::StdRng;
-use rand::{thread_rng, Rng, RngCore, SeedableRng};
+use rand::{RngCore, SeedableRng};
 use getrandom::register_custom_getrandom;
 // See here : https://forum.dfinity.org/t/issue-about-generate-random-string-panicked-at-could-not-initialize-thread-rng-getrandom-this-target-is-not-supported/15198/8?u=kinicdevcontributor
 fn custom_getrandom(buf: &mut [u8]) -> Result<(), getrandom::Error> {
@@ -31,8 +45,17 @@ register_custom_getrandom!(custom_getrandom);
 
 
 const WASM_PAGE_SIZE: u64 = 65536;
-const MISSING_CHUNKS_RESPONCE_SIZE: usize = 2 * MIB as usize;
-// const MISSING_CHUNKS_RESPONCE_SIZE: usize = 10 as usize;
+const METADATA_MAGIC: &[u8; 4] = b"VMTD";
+const METADATA_VERSION: u32 = 1;
+const MAX_MISSING_CHUNK_RANGES: u64 = 10_000;
+const HASH_BYTE_SIZE: usize = 32;
+const MAX_PAYLOAD_BYTE_SIZE: usize = 4 * KIB as usize;
+const MAX_SEARCH_BATCH_SIZE: usize = 256;
+/// Longer candidate lists barely improve the recall, but can exhaust the instructions of a query.
+const MAX_SEARCH_SIZE_L: u64 = 1_000;
+const MAX_INSERT_BATCH_SIZE: usize = 100;
+// Query calls are limited to 5 billion instructions, keep some for encoding the reply.
+const SEARCH_BATCH_INSTRUCTION_LIMIT: u64 = 4_500_000_000;
 
 thread_local! {
     // The memory manager is used for simulating multiple memories. Given a `MemoryId` it can
@@ -48,29 +71,244 @@ thread_local! {
             Metadata::None
         ).unwrap()
     );
-    static RNG:         RefCell<StdRng> = RefCell::new(StdRng::from_seed(thread_rng().gen()));
+    static ADMINS:      RefCell<StableBTreeMap<Principal, Role, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))))
+    );
+    // `thread_rng` would come back here through `custom_getrandom`, so start from a fixed seed
+    // until `seed_rng` replaces it with one from `raw_rand`.
+    static RNG:         RefCell<StdRng> = RefCell::new(StdRng::from_seed([0; 32]));
+}
+
+/// One of the two storage regions holding a graph. While one of them serves `search`,
+/// the other one can receive the next graph and be promoted in a single message.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Blue,
+    Green,
+}
+
+impl Slot {
+    fn other(self) -> Self {
+        match self {
+            Slot::Blue => Slot::Green,
+            Slot::Green => Slot::Blue,
+        }
+    }
+
+    fn storage_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(0),
+            Slot::Green => MemoryId::new(2),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// SHA-256 of every chunk, `HASH_BYTE_SIZE` bytes each, in chunk order.
+    fn chunk_hashes_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(3),
+            Slot::Green => MemoryId::new(4),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// Which chunks of the graph being uploaded into this slot are already written.
+    fn uploaded_chunks(self) -> StableBitmap {
+        StableBitmap::init(self.uploaded_chunks_memory())
+    }
+
+    fn uploaded_chunks_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(7),
+            Slot::Green => MemoryId::new(8),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// The external ID and payload of each node, keyed by node index.
+    fn documents(self) -> StableBTreeMap<u32, Document, VirtualMemory<DefaultMemoryImpl>> {
+        StableBTreeMap::init(self.documents_memory())
+    }
+
+    fn clear_documents(self) {
+        let _: StableBTreeMap<u32, Document, _> = StableBTreeMap::new(self.documents_memory());
+    }
+
+    fn documents_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(5),
+            Slot::Green => MemoryId::new(6),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    fn attributes(self) -> StableBTreeMap<u32, Attributes, VirtualMemory<DefaultMemoryImpl>> {
+        StableBTreeMap::init(self.attributes_memory())
+    }
+
+    fn clear_attributes(self) {
+        let _: StableBTreeMap<u32, Attributes, _> = StableBTreeMap::new(self.attributes_memory());
+    }
+
+    fn attributes_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(15),
+            Slot::Green => MemoryId::new(16),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// Nodes removed with `delete`, which searches still walk through but never return. `None` for
+    /// an index uploaded before deletes existed, until its first `delete`.
+    fn tombstones(self) -> Option<StableBitmap> {
+        let memory = self.tombstones_memory();
+        (memory.size() > 0).then(|| StableBitmap::init(memory))
+    }
+
+    fn tombstones_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(17),
+            Slot::Green => MemoryId::new(18),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// The deletes the last `merge` unlinked from the graph, which can no longer be undone.
+    fn merged_deletes(self) -> Option<StableBitmap> {
+        let memory = self.merged_deletes_memory();
+        (memory.size() > 0).then(|| StableBitmap::init(memory))
+    }
+
+    fn merged_deletes_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(21),
+            Slot::Green => MemoryId::new(22),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// Vectors added with `insert`, searched next to the graph until they are part of it.
+    fn delta(self) -> DeltaNodes {
+        StableBTreeMap::init(self.delta_memory())
+    }
+
+    fn clear_delta(self) {
+        let _: DeltaNodes = StableBTreeMap::new(self.delta_memory());
+    }
+
+    fn delta_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(19),
+            Slot::Green => MemoryId::new(20),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+
+    /// Holds the `GraphHeader` uploaded with `upload_header`.
+    fn header_memory(self) -> VirtualMemory<DefaultMemoryImpl> {
+        let memory_id = match self {
+            Slot::Blue => MemoryId::new(13),
+            Slot::Green => MemoryId::new(14),
+        };
+        MEMORY_MANAGER.with(|m| m.borrow().get(memory_id))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum DocumentId {
+    Text(String),
+    Nat64(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Document {
+    id: DocumentId,
+    payload: Option<Vec<u8>>,
+}
+
+impl Storable for Document {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// What a principal in `ADMINS` may do. Controllers of the canister are always `Admin`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Role {
+    /// May upload indexes and switch `search` over to them.
+    Uploader,
+    /// May also reset the canister and edit `ADMINS`.
+    Admin,
+}
+
+impl Storable for Role {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        // A byte this wasm does not know never grants more than uploading.
+        match bytes[0] {
+            1 => Role::Admin,
+            _ => Role::Uploader,
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded { max_size: 1, is_fixed_size: true };
+}
+
+#[derive(CandidType, Deserialize)]
+struct SearchResult {
+    id: DocumentId,
+    distance: f32,
+    payload: Option<Vec<u8>>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 struct LoadingMetadata {
-    // uploaded_chunks: BitVec<u8, Lsb0>,
-    uploaded_chunks: Vec<u8>, // serialized BitVec
+    slot: Slot,
+    num_chunks: u64, // which of them are uploaded is kept in `Slot::uploaded_chunks`
     chunk_byte_size: u64,
+    index_digest: Vec<u8>, // SHA-256 of the concatenated chunk hashes
+    num_chunk_hashes: u64, // how many, in chunk order, `upload_chunk_hashes` has stored
+    created_at: u64, // nanoseconds since the epoch, when `initialize` was called
 
     medoid_node_index: u32,
     sector_byte_size: u64,
     num_vectors: u64,
     vector_dim: u64,
     edge_degrees: u64,
+    metric: Metric,
+    label_entry_points: Vec<LabelEntryPoint>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 struct RunningMetadata {
+    slot: Slot,
+    chunk_byte_size: u64,
+    index_digest: Vec<u8>,
+    created_at: u64,
     medoid_node_index: u32,
     sector_byte_size: u64,
     num_vectors: u64,
     vector_dim: u64,
     edge_degrees: u64,
+    metric: Metric,
+    /// Where `search_filtered` starts for each label. Indexes from before the table existed are
+    /// migrated with none, so their filtered searches start from the medoid.
+    label_entry_points: Vec<LabelEntryPoint>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct StagingMetadata {
+    running: RunningMetadata,
+    loading: LoadingMetadata,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -78,19 +316,294 @@ enum Metadata {
     None,
     Loading(LoadingMetadata),
     Running(RunningMetadata),
+    /// The running index keeps serving while the next one is uploaded into the other slot.
+    Staging(StagingMetadata),
+}
+
+/// `Metadata` as it was stored before it had a version. This covers both the first layout, which
+/// had no slots, digests or metrics, and the one which kept the upload bitmap inline; the fields
+/// missing from the first one decode as `None`.
+#[derive(CandidType, Deserialize)]
+enum UnversionedMetadata {
+    None,
+    Loading(candid::Reserved),
+    Running(UnversionedRunningMetadata),
+    Staging(UnversionedStagingMetadata),
+}
+
+#[derive(CandidType, Deserialize)]
+struct UnversionedRunningMetadata {
+    slot: Option<Slot>,
+    chunk_byte_size: Option<u64>,
+    index_digest: Option<Vec<u8>>,
+    created_at: Option<u64>,
+    medoid_node_index: u32,
+    sector_byte_size: u64,
+    num_vectors: u64,
+    vector_dim: u64,
+    edge_degrees: u64,
+    metric: Option<Metric>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct UnversionedStagingMetadata {
+    running: UnversionedRunningMetadata,
+}
+
+impl UnversionedMetadata {
+    /// Running indexes are kept as they are: their graph was only ever written to `Slot::Blue`,
+    /// or to the slot recorded with it. An upload in progress can not be resumed, because its
+    /// bitmap was never moved out of `Metadata`, so it has to start over with `initialize`.
+    fn migrate(self) -> Metadata {
+        match self {
+            UnversionedMetadata::None | UnversionedMetadata::Loading(_) => Metadata::None,
+            UnversionedMetadata::Running(running) | UnversionedMetadata::Staging(UnversionedStagingMetadata { running }) => {
+                Metadata::Running(running.migrate())
+            }
+        }
+    }
+}
+
+impl UnversionedRunningMetadata {
+    fn migrate(self) -> RunningMetadata {
+        RunningMetadata {
+            slot: self.slot.unwrap_or(Slot::Blue),
+            // Indexes uploaded before digests existed report an empty digest and a chunk byte
+            // size of 0, which `tool verify` reports as no digest recorded.
+            chunk_byte_size: self.chunk_byte_size.unwrap_or(0),
+            index_digest: self.index_digest.unwrap_or_default(),
+            created_at: self.created_at.unwrap_or(0),
+            medoid_node_index: self.medoid_node_index,
+            sector_byte_size: self.sector_byte_size,
+            num_vectors: self.num_vectors,
+            vector_dim: self.vector_dim,
+            edge_degrees: self.edge_degrees,
+            metric: self.metric.unwrap_or(Metric::Euclidean),
+            label_entry_points: vec![],
+        }
+    }
+}
+
+impl Metadata {
+    fn state(&self) -> State {
+        match self {
+            Metadata::None => State::None,
+            Metadata::Loading(_) => State::Loading,
+            Metadata::Running(_) => State::Running,
+            Metadata::Staging(_) => State::Staging,
+        }
+    }
+
+    fn wrong_state(&self, expected: &[State]) -> VectuneError {
+        VectuneError::WrongState {
+            expected: expected.to_vec(),
+            actual: self.state(),
+        }
+    }
+
+    fn loading_metadata(&self) -> VectuneResult<&LoadingMetadata> {
+        match self {
+            Metadata::Loading(loading) | Metadata::Staging(StagingMetadata { loading, .. }) => Ok(loading),
+            _ => Err(self.wrong_state(&[State::Loading, State::Staging])),
+        }
+    }
+
+    fn loading_metadata_mut(&mut self) -> VectuneResult<&mut LoadingMetadata> {
+        match self {
+            Metadata::Loading(loading) | Metadata::Staging(StagingMetadata { loading, .. }) => Ok(loading),
+            _ => Err(self.wrong_state(&[State::Loading, State::Staging])),
+        }
+    }
+
+    fn running_metadata(&self) -> VectuneResult<&RunningMetadata> {
+        match self {
+            Metadata::Running(running) | Metadata::Staging(StagingMetadata { running, .. }) => Ok(running),
+            _ => Err(self.wrong_state(&[State::Running, State::Staging])),
+        }
+    }
+}
+
+impl LoadingMetadata {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        slot: Slot,
+        num_chunks: u64,
+        chunk_byte_size: u64,
+        index_digest: Vec<u8>,
+        medoid_node_index: u32,
+        sector_byte_size: u64,
+        num_vectors: u64,
+        vector_dim: u64,
+        edge_degrees: u64,
+        metric: Metric,
+        label_entry_points: Vec<LabelEntryPoint>,
+    ) -> Self {
+        Self {
+            slot,
+            num_chunks,
+            chunk_byte_size,
+            index_digest,
+            num_chunk_hashes: 0,
+            created_at: ic_cdk::api::time(),
+
+            medoid_node_index,
+            sector_byte_size,
+            num_vectors,
+            vector_dim,
+            edge_degrees,
+            metric,
+            label_entry_points,
+        }
+    }
+
+    fn num_missing_chunks(&self) -> u64 {
+        self.slot.uploaded_chunks().count_zeros()
+    }
+
+    /// Stores the hashes of the chunks from `first_chunk_index` on. Hashes are stored in chunk
+    /// order without gaps, and a stored hash cannot change since chunks may have been checked
+    /// against it already.
+    fn write_chunk_hashes(&mut self, first_chunk_index: u64, chunk_hashes: &[u8]) -> VectuneResult<()> {
+        if chunk_hashes.len() % HASH_BYTE_SIZE != 0 {
+            return Err(VectuneError::InvalidArgument("chunk_hashes must be a sequence of SHA-256".to_string()));
+        }
+        let num_hashes = (chunk_hashes.len() / HASH_BYTE_SIZE) as u64;
+        let num_stored = self.num_chunk_hashes;
+        if first_chunk_index > num_stored {
+            return Err(VectuneError::ChunkHashMissing { chunk_index: num_stored });
+        }
+        if first_chunk_index + num_hashes > self.num_chunks {
+            return Err(VectuneError::ChunkOutOfRange {
+                chunk_index: first_chunk_index + num_hashes - 1,
+                num_chunks: self.num_chunks,
+            });
+        }
+
+        let chunk_hashes_mem = self.slot.chunk_hashes_memory();
+        let offset = first_chunk_index * HASH_BYTE_SIZE as u64;
+        let mut stored_hashes = vec![0; (num_stored - first_chunk_index).min(num_hashes) as usize * HASH_BYTE_SIZE];
+        chunk_hashes_mem.read(offset, &mut stored_hashes);
+        if stored_hashes != chunk_hashes[..stored_hashes.len()] {
+            return Err(VectuneError::InvalidArgument("chunk_hashes differ from the ones uploaded before".to_string()));
+        }
+
+        chunk_hashes_mem.write(offset, chunk_hashes);
+        self.num_chunk_hashes = num_stored.max(first_chunk_index + num_hashes);
+        Ok(())
+    }
+
+    /// Checks that every chunk is uploaded, then hashes the chunk hashes stored by
+    /// `upload_chunk_hashes` and compares them with `index_digest`.
+    ///
+    /// The graph itself is too large to be re-hashed within the instruction limit of one message,
+    /// but every chunk has already been checked against its hash by `upload_chunk`.
+    ///
+    /// Last, the uploaded graph header has to describe the same graph.
+    fn verify(&self) -> VectuneResult<()> {
+        let missing_chunks = self.num_missing_chunks();
+        if missing_chunks > 0 {
+            return Err(VectuneError::UploadIncomplete { missing_chunks });
+        }
+        if self.num_chunk_hashes < self.num_chunks {
+            return Err(VectuneError::ChunkHashMissing { chunk_index: self.num_chunk_hashes });
+        }
+
+        let mut chunk_hashes = vec![0; self.num_chunks as usize * HASH_BYTE_SIZE];
+        self.slot.chunk_hashes_memory().read(0, &mut chunk_hashes);
+        if Sha256::digest(&chunk_hashes).as_slice() != self.index_digest.as_slice() {
+            return Err(VectuneError::DigestMismatch);
+        }
+
+        let mut header = [0; HEADER_BYTE_SIZE];
+        self.slot.header_memory().read(0, &mut header);
+        self.check_header(&GraphHeader::decode(&header)?)
+    }
+
+    /// Compares the header of the graph file with what `initialize` was told about the graph, and
+    /// checks that the nodes it describes fill the uploaded chunks.
+    fn check_header(&self, header: &GraphHeader) -> VectuneResult<()> {
+        let fields = [
+            ("vector_dim", header.vector_dim, self.vector_dim),
+            ("edge_degrees", header.edge_degrees, self.edge_degrees),
+            ("sector_byte_size", header.sector_byte_size, self.sector_byte_size),
+            ("num_vectors", header.num_vectors, self.num_vectors),
+            ("medoid_node_index", header.medoid_node_index as u64, self.medoid_node_index as u64),
+        ];
+        for (name, in_header, in_metadata) in fields {
+            if in_header != in_metadata {
+                return Err(VectuneError::InvalidHeader(format!(
+                    "the graph has {name} {in_header}, but initialize was given {in_metadata}"
+                )));
+            }
+        }
+        if header.metric != self.metric {
+            return Err(VectuneError::InvalidHeader(format!(
+                "the graph was built for {:?}, but initialize was given {:?}",
+                header.metric, self.metric
+            )));
+        }
+        if header.index_digest != self.index_digest {
+            return Err(VectuneError::InvalidHeader("the header belongs to another graph".to_string()));
+        }
+
+        // Every chunk but the last one is full, so the graph ends within the last chunk.
+        let byte_size = header::graph_byte_size(header.num_vectors, header.vector_dim, header.edge_degrees, header.sector_byte_size)?;
+        let uploaded_byte_size = self.num_chunks.saturating_mul(self.chunk_byte_size);
+        if byte_size > uploaded_byte_size || byte_size <= uploaded_byte_size - self.chunk_byte_size.min(uploaded_byte_size) {
+            return Err(VectuneError::InvalidHeader(format!(
+                "{} nodes take {byte_size} bytes, which do not fill {} chunks of {} bytes",
+                header.num_vectors, self.num_chunks, self.chunk_byte_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn to_running_metadata(&self) -> RunningMetadata {
+        RunningMetadata {
+            slot: self.slot,
+            chunk_byte_size: self.chunk_byte_size,
+            index_digest: self.index_digest.clone(),
+            created_at: self.created_at,
+            medoid_node_index: self.medoid_node_index,
+            sector_byte_size: self.sector_byte_size,
+            num_vectors: self.num_vectors,
+            vector_dim: self.vector_dim,
+            edge_degrees: self.edge_degrees,
+            metric: self.metric,
+            label_entry_points: self.label_entry_points.clone(),
+        }
+    }
 }
 
+/// `Metadata` is stored as `METADATA_MAGIC`, `METADATA_VERSION` as little endian u32, and the
+/// Candid encoding of the layout of that version. Older wasms stored the bare Candid encoding.
 impl Storable for Metadata {
     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+        let mut bytes = METADATA_MAGIC.to_vec();
+        bytes.extend_from_slice(&METADATA_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&Encode!(self).unwrap());
+        Cow::Owned(bytes)
     }
 
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        if !bytes.starts_with(METADATA_MAGIC) {
+            return Decode!(bytes.as_ref(), UnversionedMetadata).unwrap().migrate();
+        }
+
+        if bytes.len() < 8 {
+            panic!("Metadata is truncated, it has {} bytes", bytes.len());
+        }
+        let version = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        match version {
+            1 => Decode!(&bytes[8..], Self).expect("Metadata version 1 does not decode"),
+            // Trapping in `post_upgrade` rolls the upgrade back instead of misreading the index.
+            _ => panic!("Metadata version {version} is newer than this wasm, which reads up to {METADATA_VERSION}"),
+        }
     }
 
     const BOUND: Bound = Bound::Bounded {
-        max_size: 125_000_000, // max size, 1 billion bits
+        max_size: 125_000_000, // max size, older layouts kept a bitmap of 1 billion chunks in here
         is_fixed_size: false,
     };
 }
@@ -109,8 +622,8 @@ impl StorageTrait for Storage {
         self.storage_mem.read(offset as u64, dst);
     }
 
-    fn write(&self, _offset: u64, _src: &[u8]) {
-        todo!()
+    fn write(&self, offset: u64, src: &[u8]) {
+        self.storage_mem.write(offset, src);
     }
 
     fn sector_byte_size(&self) -> usize {
@@ -118,261 +631,1182 @@ impl StorageTrait for Storage {
     }
 }
 
-#[query]
-fn status_code() -> u8 {
+fn open_graph_store(metadata: &RunningMetadata) -> GraphStore<Storage> {
+    let storage = Storage {
+        storage_mem: metadata.slot.storage_memory(),
+        sector_byte_size: metadata.sector_byte_size as usize,
+    };
+    // A merge links the nodes of the delta index into the graph, under the node indices following
+    // `num_vectors`, before it counts them in.
+    let num_vectors = metadata.num_vectors + metadata.slot.delta().len();
+
+    GraphStore::new(
+        num_vectors as usize,
+        metadata.vector_dim as usize,
+        metadata.edge_degrees as usize,
+        storage,
+    )
+}
+
+fn check_query_dim(query_vector: &[f32], metadata: &RunningMetadata) -> VectuneResult<()> {
+    if query_vector.len() as u64 != metadata.vector_dim {
+        return Err(VectuneError::DimensionMismatch {
+            expected: metadata.vector_dim,
+            actual: query_vector.len() as u64,
+        });
+    }
+    Ok(())
+}
+
+fn check_search_arguments(top_k: u64, size_l: u64) -> VectuneResult<()> {
+    if size_l == 0 || size_l > MAX_SEARCH_SIZE_L {
+        return Err(VectuneError::InvalidArgument(format!("size_l must be between 1 and {MAX_SEARCH_SIZE_L}")));
+    }
+    if top_k > size_l {
+        return Err(VectuneError::InvalidArgument("top_k must not be larger than size_l".to_string()));
+    }
+    Ok(())
+}
+
+/// Checks a search of the running index before it is counted against a quota and run, so that
+/// the functions running it can take their arguments as they are.
+fn check_search(query_vector: &[f32], top_k: u64, size_l: u64) -> VectuneResult<()> {
+    check_search_batch(std::slice::from_ref(query_vector), top_k, size_l)
+}
+
+fn check_search_batch<V: AsRef<[f32]>>(queries: &[V], top_k: u64, size_l: u64) -> VectuneResult<()> {
+    check_search_arguments(top_k, size_l)?;
+    if queries.len() > MAX_SEARCH_BATCH_SIZE {
+        return Err(VectuneError::InvalidArgument(format!("search_batch accepts up to {MAX_SEARCH_BATCH_SIZE} queries")));
+    }
+
     METADATA.with(|metadata| {
         let metadata = metadata.borrow();
+        let metadata = metadata.get().running_metadata()?;
+        queries.iter().try_for_each(|query_vector| check_query_dim(query_vector.as_ref(), metadata))
+    })
+}
 
-        match *metadata.get() {
-            Metadata::None => {
-                0
-            },
-            Metadata::Loading(_) => {
-                1
-            },
-            Metadata::Running(_) => {
-                2
-            }
+#[derive(CandidType, Deserialize)]
+struct LoadingStatus {
+    index: RunningMetadata,
+    num_uploaded_chunks: u64,
+    num_chunks: u64,
+    num_chunk_hashes: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Status {
+    state: State,
+    running: Option<RunningMetadata>,
+    loading: Option<LoadingStatus>,
+    /// Nodes of the running index removed with `delete`.
+    deleted_count: u64,
+    /// Nodes added to the running index with `insert`.
+    inserted_count: u64,
+    merge: Option<MergeProgress>,
+    stable_memory_byte_size: u64,
+    version: String,
+}
+
+#[query]
+fn status() -> Status {
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let metadata = metadata.get();
+
+        Status {
+            state: metadata.state(),
+            running: metadata.running_metadata().ok().cloned(),
+            loading: metadata.loading_metadata().ok().map(|loading_metadata| LoadingStatus {
+                index: loading_metadata.to_running_metadata(),
+                num_uploaded_chunks: loading_metadata.num_chunks - loading_metadata.num_missing_chunks(),
+                num_chunks: loading_metadata.num_chunks,
+                num_chunk_hashes: loading_metadata.num_chunk_hashes,
+            }),
+            deleted_count: metadata
+                .running_metadata()
+                .ok()
+                .and_then(|running_metadata| running_metadata.slot.tombstones())
+                .map_or(0, |tombstones| tombstones.count_ones()),
+            inserted_count: metadata
+                .running_metadata()
+                .map_or(0, |running_metadata| running_metadata.slot.delta().len()),
+            merge: merge::progress(),
+            stable_memory_byte_size: ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE,
+            version: env!("CARGO_PKG_VERSION").to_string(),
         }
     })
 }
 
-async fn get_controllers() -> Vec<Principal> {
-    let status: ic_types::CanisterStatusResponse =
-        ic_types::canister_status(ic_types::CanisterIdRecord {
-            canister_id: ic_cdk::id(),
-        })
-        .await
-        .unwrap()
-        .0;
+#[init]
+fn init() {
+    ADMINS.with(|admins| admins.borrow_mut().insert(ic_cdk::caller(), Role::Admin));
+    schedule_seed_rng(Duration::ZERO);
+}
+
+/// Every piece of state lives in stable structures, so there is nothing to save in a
+/// `pre_upgrade`. `Metadata` is rewritten here so that a layout written by an older wasm is
+/// migrated once, instead of on every read.
+#[post_upgrade]
+fn post_upgrade() {
+    METADATA.with(|metadata| {
+        let mut metadata = metadata.borrow_mut();
+        let current = metadata.get().clone();
+        let _ = metadata.set(current);
+    });
+    schedule_seed_rng(Duration::ZERO);
+    merge::resume();
+}
+
+// `raw_rand` is an inter-canister call, which `init` and `post_upgrade` can not make.
+fn schedule_seed_rng(delay: Duration) {
+    ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(seed_rng()));
+}
+
+async fn seed_rng() {
+    match ic_cdk::api::management_canister::main::raw_rand().await {
+        Ok((seed,)) => {
+            let seed: [u8; 32] = seed[..32].try_into().unwrap();
+            RNG.with(|rng| *rng.borrow_mut() = StdRng::from_seed(seed));
+        }
+        Err((code, message)) => {
+            ic_cdk::println!("raw_rand failed, retrying: {code:?}: {message}");
+            schedule_seed_rng(Duration::from_secs(1));
+        }
+    }
+}
+
+fn caller_role() -> Option<Role> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        return Some(Role::Admin);
+    }
+    ADMINS.with(|admins| admins.borrow().get(&caller))
+}
+
+fn assert_role(role: Role) -> VectuneResult<()> {
+    match caller_role() {
+        Some(caller_role) if caller_role >= role => Ok(()),
+        _ => Err(VectuneError::NotOwner),
+    }
+}
 
-        status.settings.controllers
+fn assert_owner() -> VectuneResult<()> {
+    assert_role(Role::Admin)
 }
 
-async fn assert_owner() {
-    let controllers = get_controllers().await;
-    if !is_owner(&controllers) {
-        trap("You are not controller")
+fn assert_uploader() -> VectuneResult<()> {
+    assert_role(Role::Uploader)
+}
+
+/// Gives `principal` the given role, replacing the one it had.
+#[update]
+fn add_admin(principal: Principal, role: Role) -> VectuneResult<()> {
+    assert_owner()?;
+    if principal == Principal::anonymous() {
+        return Err(VectuneError::InvalidArgument("the anonymous principal can not be an admin".to_string()));
     }
+
+    ADMINS.with(|admins| admins.borrow_mut().insert(principal, role));
+
+    Ok(())
+}
+
+/// Takes the role of `principal` away. Controllers stay admins regardless of the list.
+#[update]
+fn remove_admin(principal: Principal) -> VectuneResult<()> {
+    assert_owner()?;
+
+    ADMINS.with(|admins| admins.borrow_mut().remove(&principal));
+
+    Ok(())
+}
+
+#[query]
+fn admins() -> VectuneResult<Vec<(Principal, Role)>> {
+    assert_owner()?;
+
+    Ok(ADMINS.with(|admins| admins.borrow().iter().collect()))
 }
 
+/// Starts uploading a graph of `num_chunks` chunks.
+///
+/// `index_digest` is the SHA-256 of the SHA-256 of every chunk concatenated in chunk order. The
+/// chunk hashes themselves are too large for one message and follow with `upload_chunk_hashes`.
+/// `upload_chunk` rejects chunks which do not match their hash and `start` refuses an index whose
+/// hashes do not match `index_digest`.
+///
+/// `metric` is the distance `search` ranks the nodes with, and the one its scores are reported in.
+///
+/// `label_entry_points` holds a start node for the labels `search_filtered` should not reach
+/// from the medoid, usually the medoid of the nodes carrying the label.
 #[update]
-async fn initialize(
+#[allow(clippy::too_many_arguments)]
+fn initialize(
     num_chunks: u64,
     chunk_byte_size: u64,
+    index_digest: Vec<u8>,
     medoid_node_index: u32,
     sector_byte_size: u64,
     num_vectors: u64,
     vector_dim: u64,
     edge_degrees: u64,
-) {
-    // let status: ic_types::CanisterStatusResponse =
-    //     ic_types::canister_status(ic_types::CanisterIdRecord {
-    //         canister_id: ic_cdk::id(),
-    //     })
-    //     .await
-    //     .unwrap()
-    //     .0;
-    // let controllers = Rc::new(status.settings.controllers);
-
-    assert_owner().await;
+    metric: Metric,
+    label_entry_points: Vec<LabelEntryPoint>,
+) -> VectuneResult<()> {
+    assert_uploader()?;
+    check_index_digest(&index_digest)?;
+    filter::check_label_entry_points(&label_entry_points, num_vectors)?;
 
     METADATA.with(|metadata| {
         let mut metadata = metadata.borrow_mut();
         let Metadata::None = *metadata.get() else {
-            trap("Metadata is not None")
+            return Err(metadata.get().wrong_state(&[State::None]))
         };
 
-        let _ = metadata.set(Metadata::Loading(LoadingMetadata {
-            uploaded_chunks: bincode::serialize(&bitvec![u8, Lsb0; 0; num_chunks as usize]).unwrap(),
-            chunk_byte_size,
+        prepare_slot(Slot::Blue, num_chunks, chunk_byte_size, num_vectors)?;
 
+        let _ = metadata.set(Metadata::Loading(LoadingMetadata::new(
+            Slot::Blue,
+            num_chunks,
+            chunk_byte_size,
+            index_digest,
             medoid_node_index,
             sector_byte_size,
             num_vectors,
             vector_dim,
             edge_degrees,
-        }));
-    });
-
-    let storage_mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
-    let num_pages = (num_chunks * chunk_byte_size + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE;
-
-    // wip 現在のページ数
+            metric,
+            label_entry_points,
+        )));
 
-    storage_mem.grow(num_pages);
+        Ok(())
+    })
 }
 
+/// Same as `initialize`, but keeps the running index serving `search` and uploads the new graph
+/// into the other slot. The new graph replaces the running one with `promote`.
 #[update]
-async fn upload_chunk(chunk: Vec<u8>, chunk_index: u64) {
-    assert_owner().await;
+#[allow(clippy::too_many_arguments)]
+fn initialize_staging(
+    num_chunks: u64,
+    chunk_byte_size: u64,
+    index_digest: Vec<u8>,
+    medoid_node_index: u32,
+    sector_byte_size: u64,
+    num_vectors: u64,
+    vector_dim: u64,
+    edge_degrees: u64,
+    metric: Metric,
+    label_entry_points: Vec<LabelEntryPoint>,
+) -> VectuneResult<()> {
+    assert_uploader()?;
+    check_index_digest(&index_digest)?;
+    filter::check_label_entry_points(&label_entry_points, num_vectors)?;
 
     METADATA.with(|metadata| {
         let mut metadata = metadata.borrow_mut();
-        let Metadata::Loading(mut loading_metadata) = metadata.get().clone() else {
-            trap("Metadata is not Loading")
+        let Metadata::Running(running_metadata) = metadata.get().clone() else {
+            return Err(metadata.get().wrong_state(&[State::Running]))
         };
-        let mut uploaded_chunks: BitVec<u8, Lsb0> = bincode::deserialize(&loading_metadata.uploaded_chunks).unwrap();
-
-        assert!(chunk.len() <= loading_metadata.chunk_byte_size as usize);
-
-        let storage_mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
-        let offset = loading_metadata.chunk_byte_size * chunk_index;
-        let src = &chunk[..];
-
-        storage_mem.write(offset, src);
-        uploaded_chunks.set(chunk_index as usize, true);
-
-        loading_metadata.uploaded_chunks = bincode::serialize(&uploaded_chunks).unwrap();
+        let slot = running_metadata.slot.other();
+
+        prepare_slot(slot, num_chunks, chunk_byte_size, num_vectors)?;
+
+        let _ = metadata.set(Metadata::Staging(StagingMetadata {
+            running: running_metadata,
+            loading: LoadingMetadata::new(
+                slot,
+                num_chunks,
+                chunk_byte_size,
+                index_digest,
+                medoid_node_index,
+                sector_byte_size,
+                num_vectors,
+                vector_dim,
+                edge_degrees,
+                metric,
+                label_entry_points,
+            ),
+        }));
 
-        let _ = metadata.set(Metadata::Loading(loading_metadata));
+        Ok(())
     })
 }
 
-#[query]
-fn missing_chunks(section: u64) -> Option<Vec<u8>> {
+fn check_index_digest(index_digest: &[u8]) -> VectuneResult<()> {
+    if index_digest.len() != HASH_BYTE_SIZE {
+        return Err(VectuneError::InvalidArgument("index_digest must be a SHA-256".to_string()));
+    }
+    Ok(())
+}
 
-    METADATA.with(|metadata| {
-        let metadata = metadata.borrow();
-        let Metadata::Loading(loading_metadata) = &*metadata.get() else {
-            trap("Metadata is not Loading")
-        };
+/// Grows the memories of `slot` before any metadata points at it, so that a failure leaves the
+/// canister as it was.
+fn prepare_slot(slot: Slot, num_chunks: u64, chunk_byte_size: u64, num_vectors: u64) -> VectuneResult<()> {
+    grow_memory(&slot.storage_memory(), num_chunks * chunk_byte_size)?;
+    StableBitmap::new(slot.uploaded_chunks_memory(), num_chunks)?;
+    StableBitmap::new(slot.tombstones_memory(), num_vectors)?;
+    StableBitmap::new(slot.merged_deletes_memory(), num_vectors)?;
 
-        let start = MISSING_CHUNKS_RESPONCE_SIZE * section as usize;
+    grow_memory(&slot.chunk_hashes_memory(), num_chunks * HASH_BYTE_SIZE as u64)?;
 
-        if start >= loading_metadata.uploaded_chunks.len() {
-            return None
-        }
+    slot.clear_documents();
+    slot.clear_attributes();
+    slot.clear_delta();
 
-        let end = std::cmp::min(start + MISSING_CHUNKS_RESPONCE_SIZE, loading_metadata.uploaded_chunks.len());
+    let header_mem = slot.header_memory();
+    grow_memory(&header_mem, HEADER_BYTE_SIZE as u64)?;
+    header_mem.write(0, &[0; HEADER_BYTE_SIZE]);
 
-        Some(loading_metadata.uploaded_chunks[start..end].to_vec())
+    Ok(())
+}
+
+fn grow_memory(memory: &VirtualMemory<DefaultMemoryImpl>, byte_size: u64) -> VectuneResult<()> {
+    let num_pages = byte_size.div_ceil(WASM_PAGE_SIZE);
+
+    // A slot which has held a graph before still has its pages, so only grow the difference.
+    let current_pages = memory.size();
+    if num_pages > current_pages && memory.grow(num_pages - current_pages) == -1 {
+        return Err(VectuneError::OutOfMemory);
+    }
+    Ok(())
+}
+
+/// Stores the SHA-256 of the chunks from `first_chunk_index` on, concatenated in chunk order.
+///
+/// The hashes have to be uploaded in order, and a chunk can only be uploaded once its hash is.
+/// Uploading hashes again is a no-op if they are the same as the stored ones.
+#[update]
+fn upload_chunk_hashes(first_chunk_index: u64, chunk_hashes: Vec<u8>) -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let mut metadata = metadata.borrow_mut();
+        let mut updated_metadata = metadata.get().clone();
+        updated_metadata.loading_metadata_mut()?.write_chunk_hashes(first_chunk_index, &chunk_hashes)?;
+        let _ = metadata.set(updated_metadata);
+
+        Ok(())
     })
 }
 
+/// Writes one chunk of the graph being uploaded.
+///
+/// Every chunk but the last one has to be exactly `chunk_byte_size` long. Uploading a chunk again
+/// is a no-op if the bytes are the same as the stored ones, and a `ChunkConflict` otherwise.
 #[update]
-async fn start() {
-    assert_owner().await;
+fn upload_chunk(chunk: Vec<u8>, chunk_index: u64) -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let loading_metadata = metadata.get().loading_metadata()?;
+
+        write_chunk(loading_metadata, chunk, chunk_index)
+    })
+}
+
+/// Same as `upload_chunk` for several chunks in one message, so that small chunks do not pay for
+/// a message and an owner check each. The chunks are written in order, and the ones before a
+/// failing chunk stay written.
+#[update]
+fn upload_chunks(chunks: Vec<(u64, Vec<u8>)>) -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let loading_metadata = metadata.get().loading_metadata()?;
+
+        for (chunk_index, chunk) in chunks {
+            write_chunk(loading_metadata, chunk, chunk_index)?;
+        }
+
+        Ok(())
+    })
+}
+
+fn write_chunk(loading_metadata: &LoadingMetadata, chunk: Vec<u8>, chunk_index: u64) -> VectuneResult<()> {
+    let uploaded_chunks = loading_metadata.slot.uploaded_chunks();
+
+    let num_chunks = loading_metadata.num_chunks;
+    if chunk_index >= num_chunks {
+        return Err(VectuneError::ChunkOutOfRange { chunk_index, num_chunks });
+    }
+    if chunk_index >= loading_metadata.num_chunk_hashes {
+        return Err(VectuneError::ChunkHashMissing { chunk_index });
+    }
+
+    let chunk_byte_size = loading_metadata.chunk_byte_size as usize;
+    let is_last_chunk = chunk_index == num_chunks - 1;
+    if chunk.len() > chunk_byte_size {
+        return Err(VectuneError::InvalidArgument("chunk is larger than chunk_byte_size".to_string()));
+    }
+    if !is_last_chunk && chunk.len() != chunk_byte_size {
+        return Err(VectuneError::InvalidArgument("only the last chunk may be shorter than chunk_byte_size".to_string()));
+    }
+    if chunk.is_empty() {
+        return Err(VectuneError::InvalidArgument("chunk is empty".to_string()));
+    }
+
+    let storage_mem = loading_metadata.slot.storage_memory();
+    let offset = loading_metadata.chunk_byte_size * chunk_index;
+
+    if uploaded_chunks.get(chunk_index) {
+        let mut stored_chunk = vec![0; chunk.len()];
+        storage_mem.read(offset, &mut stored_chunk);
+        if stored_chunk != chunk {
+            return Err(VectuneError::ChunkConflict { chunk_index });
+        }
+        return Ok(());
+    }
+
+    let mut expected_hash = [0; HASH_BYTE_SIZE];
+    loading_metadata.slot.chunk_hashes_memory().read(chunk_index * HASH_BYTE_SIZE as u64, &mut expected_hash);
+    if Sha256::digest(&chunk).as_slice() != expected_hash.as_slice() {
+        return Err(VectuneError::ChunkHashMismatch { chunk_index });
+    }
+
+    let src = &chunk[..];
+
+    storage_mem.write(offset, src);
+    uploaded_chunks.set(chunk_index);
+
+    Ok(())
+}
+
+/// Stores the header of the graph being uploaded, see `header::GraphHeader`. It is checked right
+/// away, and again by `start` and `promote`.
+#[update]
+fn upload_header(header: Vec<u8>) -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let loading_metadata = metadata.get().loading_metadata()?;
+
+        loading_metadata.check_header(&GraphHeader::decode(&header)?)?;
+        loading_metadata.slot.header_memory().write(0, &header);
+
+        Ok(())
+    })
+}
+
+/// Stores the external ID and an optional payload of each given node of the graph being uploaded.
+/// Uploading a node again replaces its document.
+#[update]
+fn upload_documents(documents: Vec<(u32, Document)>) -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let loading_metadata = metadata.get().loading_metadata()?;
+
+        for (node_index, document) in &documents {
+            if *node_index as u64 >= loading_metadata.num_vectors {
+                return Err(VectuneError::InvalidArgument(format!("node index {node_index} is out of range")));
+            }
+            if document.payload.as_ref().is_some_and(|payload| payload.len() > MAX_PAYLOAD_BYTE_SIZE) {
+                return Err(VectuneError::InvalidArgument(format!("payload of node {node_index} is too large")));
+            }
+        }
+
+        let mut document_map = loading_metadata.slot.documents();
+        for (node_index, document) in documents {
+            document_map.insert(node_index, document);
+        }
+
+        Ok(())
+    })
+}
+
+/// Stores the attributes `search_filtered` matches each given node against. Uploading a node again
+/// replaces its attributes.
+#[update]
+fn upload_attributes(attributes: Vec<(u32, Attributes)>) -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let loading_metadata = metadata.get().loading_metadata()?;
+
+        for (node_index, node_attributes) in &attributes {
+            if *node_index as u64 >= loading_metadata.num_vectors {
+                return Err(VectuneError::InvalidArgument(format!("node index {node_index} is out of range")));
+            }
+            if Encode!(node_attributes).unwrap().len() > MAX_PAYLOAD_BYTE_SIZE {
+                return Err(VectuneError::InvalidArgument(format!("attributes of node {node_index} are too large")));
+            }
+        }
+
+        let mut attribute_map = loading_metadata.slot.attributes();
+        for (node_index, node_attributes) in attributes {
+            attribute_map.insert(node_index, node_attributes);
+        }
+
+        Ok(())
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+struct MissingChunkRanges {
+    /// `[start, end)` ranges of chunk indices which are not uploaded yet.
+    ranges: Vec<(u64, u64)>,
+    /// Where the next call continues from, if there are more ranges than `limit`.
+    next_cursor: Option<u64>,
+    num_uploaded_chunks: u64,
+    num_chunks: u64,
+}
+
+/// Returns up to `limit` ranges of missing chunks, starting from the chunk index `cursor`.
+#[query]
+fn missing_chunk_ranges(cursor: u64, limit: u64) -> VectuneResult<MissingChunkRanges> {
+    let limit = std::cmp::min(limit, MAX_MISSING_CHUNK_RANGES) as usize;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let loading_metadata = metadata.get().loading_metadata()?;
+        let uploaded_chunks = loading_metadata.slot.uploaded_chunks();
+        let num_chunks = loading_metadata.num_chunks;
+
+        let mut ranges = Vec::new();
+        let mut index = std::cmp::min(cursor, num_chunks);
+        while index < num_chunks && ranges.len() < limit {
+            let Some(start) = uploaded_chunks.next(index, false) else {
+                index = num_chunks;
+                break;
+            };
+            let end = uploaded_chunks.next(start, true).unwrap_or(num_chunks);
+            ranges.push((start, end));
+            index = end;
+        }
+
+        Ok(MissingChunkRanges {
+            ranges,
+            next_cursor: (index < num_chunks).then_some(index),
+            num_uploaded_chunks: uploaded_chunks.count_ones(),
+            num_chunks,
+        })
+    })
+}
+
+#[update]
+fn start() -> VectuneResult<()> {
+    assert_uploader()?;
 
     METADATA.with(|metadata| {
         let mut metadata = metadata.borrow_mut();
         let Metadata::Loading(loading_metadata) = metadata.get().clone() else {
-            trap("Metadata is not Loading")
+            return Err(metadata.get().wrong_state(&[State::Loading]))
         };
 
-        let uploaded_chunks: BitVec<u8, Lsb0> = bincode::deserialize(&loading_metadata.uploaded_chunks).unwrap();
+        loading_metadata.verify()?;
 
-        if uploaded_chunks.iter().all(|bit| *bit) {
-            let _ = metadata.set(Metadata::Running(RunningMetadata {
-                medoid_node_index: loading_metadata.medoid_node_index,
-                sector_byte_size: loading_metadata.sector_byte_size,
-                num_vectors: loading_metadata.num_vectors,
-                vector_dim: loading_metadata.vector_dim,
-                edge_degrees: loading_metadata.edge_degrees,
-            }));
-        } else {
-            trap("uploading chunk is not done")
-        }
+        let _ = metadata.set(Metadata::Running(loading_metadata.to_running_metadata()));
+
+        Ok(())
+    })
+}
+
+/// Switches `search` over to the staged graph. Because the whole switch is a single write of
+/// `Metadata`, every query sees either the old or the new index, never a mix of both.
+#[update]
+fn promote() -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let mut metadata = metadata.borrow_mut();
+        let Metadata::Staging(staging_metadata) = metadata.get().clone() else {
+            return Err(metadata.get().wrong_state(&[State::Staging]))
+        };
+
+        staging_metadata.loading.verify()?;
+
+        let _ = metadata.set(Metadata::Running(staging_metadata.loading.to_running_metadata()));
+
+        Ok(())
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+struct IndexDigest {
+    chunk_byte_size: u64,
+    digest: Vec<u8>,
+}
+
+/// The digest of the running index, so that uploaders can check it against the graph file.
+#[query]
+fn index_digest() -> VectuneResult<IndexDigest> {
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let running_metadata = metadata.get().running_metadata()?;
+
+        Ok(IndexDigest {
+            chunk_byte_size: running_metadata.chunk_byte_size,
+            digest: running_metadata.index_digest.clone(),
+        })
     })
 }
 
+/// Drops the current index, and the staged one if any, and moves `Metadata` back to `None` so that
+/// `initialize` can run again.
+///
+/// The memory manager cannot give pages back, so the storage region is kept as it is and reused
+/// by the next `initialize`. Its bytes are not zeroed: every chunk of the next graph is marked as
+/// missing and gets overwritten by `upload_chunk`, and nothing past the new graph is ever read.
 #[update]
-async fn reset() {
-    assert_owner().await;
-    todo!();
+fn reset() -> VectuneResult<()> {
+    assert_owner()?;
 
-    // METADATA.with(|metadata| {
-    //     let mut metadata = metadata.borrow_mut();
-    //     let Metadata::Running(running_metadata) = &mut *metadata.get() else {
-    //         trap("Metadata is not Running")
-    //     };
+    METADATA.with(|metadata| {
+        let _ = metadata.borrow_mut().set(Metadata::None);
+    });
 
-    // })
+    Ok(())
 }
 
+/// Who may search, see `AccessPolicy`. Only admins can read it.
 #[query]
-fn search(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> Vec<(f32, u32)> {
+fn access() -> VectuneResult<(Access, Vec<(Principal, Searcher)>)> {
+    assert_owner()?;
+
+    Ok((access::access(), access::searchers()))
+}
+
+/// Sets who may search, and the quota of every caller that has none of its own.
+#[update]
+fn set_access(policy: AccessPolicy, quota: Option<Quota>) -> VectuneResult<()> {
+    assert_owner()?;
+
+    access::set_access(Access { policy, quota })
+}
+
+/// Allows `principal` to search under `AccessPolicy::AllowList`, with its own quota if any.
+#[update]
+fn add_searcher(principal: Principal, quota: Option<Quota>) -> VectuneResult<()> {
+    assert_owner()?;
 
-    // ic_cdk::println!("{}\n{}", usize::MAX, u64::MAX);
+    access::add_searcher(principal, Searcher { quota })
+}
+
+#[update]
+fn remove_searcher(principal: Principal) -> VectuneResult<()> {
+    assert_owner()?;
 
-    // ic_cdk::println!("stable_size u32: {}", ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE);
+    access::remove_searcher(&principal);
+
+    Ok(())
+}
 
-    assert!(top_k <= size_l);
+/// Removes nodes from the results of every search of the running index. The graph itself is left
+/// as it is until the next `merge`, so searches keep walking through deleted nodes to reach the
+/// others.
+///
+/// Deletes belong to the index, so an index replaced with `promote` takes them with it. Only nodes
+/// of the graph can be deleted, not the ones added with `insert` and not merged yet.
+#[update]
+fn delete(node_indices: Vec<u32>) -> VectuneResult<()> {
+    assert_uploader()?;
 
     METADATA.with(|metadata| {
         let metadata = metadata.borrow();
-        let Metadata::Running(metadata) = &*metadata.get() else {
-            trap("Metadata is not Running")
-        };
+        let running_metadata = metadata.get().running_metadata()?;
+        check_node_indices(&node_indices, running_metadata)?;
 
-        let storage_mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
-        let storage = Storage {
-            storage_mem,
-            sector_byte_size: metadata.sector_byte_size as usize,
+        let tombstones = match running_metadata.slot.tombstones() {
+            Some(tombstones) => tombstones,
+            None => StableBitmap::new(running_metadata.slot.tombstones_memory(), running_metadata.num_vectors)?,
         };
+        for node_index in node_indices {
+            tombstones.set(node_index as u64);
+        }
 
-        let unordered_graph_on_storage = GraphStore::new(
-            metadata.num_vectors as usize,
-            metadata.vector_dim as usize,
-            metadata.edge_degrees as usize,
-            storage,
-        );
+        Ok(())
+    })
+}
+
+/// Adds vectors to the running index and returns their node indices, which follow the nodes of
+/// the graph. They are searchable as soon as the call returns.
+///
+/// The vectors go into a delta index next to the graph, which every search also looks into until
+/// `merge` folds them into the graph. Like deletes, inserts belong to the index, so an index
+/// replaced with `promote` takes them with it. `search_filtered` only searches the graph, and
+/// inserted nodes have no attributes. Indexes searched with `Metric::InnerProduct` are refused.
+#[update]
+fn insert(vectors: Vec<Vec<f32>>) -> VectuneResult<Vec<u32>> {
+    assert_uploader()?;
+    if vectors.len() > MAX_INSERT_BATCH_SIZE {
+        return Err(VectuneError::InvalidArgument(format!("insert accepts up to {MAX_INSERT_BATCH_SIZE} vectors")));
+    }
+    if merge::is_running() {
+        return Err(VectuneError::MergeInProgress);
+    }
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let running_metadata = metadata.get().running_metadata()?;
+        check_prunable_metric(running_metadata)?;
+        for vector in &vectors {
+            check_query_dim(vector, running_metadata)?;
+        }
+
+        let mut delta = running_metadata.slot.delta();
+        // Node indices are u32, so a graph close to that many nodes leaves less room.
+        let max_nodes = std::cmp::min(MAX_DELTA_NODES, u32::MAX as u64 + 1 - running_metadata.num_vectors);
+        if delta.len() + vectors.len() as u64 > max_nodes {
+            return Err(VectuneError::DeltaIndexFull { max_nodes });
+        }
 
-        let mut graph = UnorderedGraph::new(unordered_graph_on_storage, metadata.medoid_node_index);
+        let node_indices = vectors
+            .into_iter()
+            .map(|vector| {
+                let delta_index = match running_metadata.metric {
+                    Metric::Euclidean => delta::insert::<Point>(&mut delta, vector),
+                    Metric::Cosine | Metric::InnerProduct => {
+                        set_simd_point_params(running_metadata);
+                        delta::insert::<SIMDPoint>(&mut delta, vector)
+                    }
+                };
+                running_metadata.num_vectors as u32 + delta_index
+            })
+            .collect();
+
+        Ok(node_indices)
+    })
+}
 
-        graph.set_size_l(size_l as usize);
+/// Returns nodes removed with `delete` to the search results. Nodes which are not deleted are
+/// skipped, and nodes a `merge` has already unlinked are refused.
+#[update]
+fn undelete(node_indices: Vec<u32>) -> VectuneResult<()> {
+    assert_uploader()?;
 
-        let (k_ann, visited) = vectune::search(&mut graph, &Point::from_f32_vec(query_vector), top_k as usize);
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let running_metadata = metadata.get().running_metadata()?;
+        check_node_indices(&node_indices, running_metadata)?;
+
+        if let Some(merged_deletes) = running_metadata.slot.merged_deletes() {
+            if let Some(node_index) = node_indices.iter().find(|&&node_index| merged_deletes.get(node_index as u64)) {
+                return Err(VectuneError::InvalidArgument(format!(
+                    "node {node_index} was unlinked from the graph by a merge and can not be undeleted"
+                )));
+            }
+        }
 
-        ic_cdk::println!("visited len: {}", visited.len());
+        if let Some(tombstones) = running_metadata.slot.tombstones() {
+            for node_index in node_indices {
+                tombstones.unset(node_index as u64);
+            }
+        }
 
-        k_ann
+        Ok(())
     })
 }
 
+/// Folds the nodes added with `insert` and the ones removed with `delete` into the graph of the
+/// running index, in the background. `status` reports the progress.
+///
+/// The merge runs in steps of one timer callback each, which keep serving searches in between.
+/// Once done, the inserted nodes are part of the graph under the same node indices, and the
+/// deleted ones are still in `deleted_count` but no longer linked from any other node. Since the
+/// graph then differs from the uploaded one, `index_digest` keeps describing the uploaded graph.
+///
+/// A merge which stops making progress is given up after a while, and `cancel_merge` stops one
+/// right away.
+#[update]
+fn merge() -> VectuneResult<()> {
+    assert_uploader()?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let running_metadata = metadata.get().running_metadata()?;
+        check_prunable_metric(running_metadata)?;
+
+        merge::start(running_metadata)
+    })
+}
+
+/// Stops the merge in progress, if any, see `merge::cancel`. `insert` is accepted again right away,
+/// and `merge` starts over.
+#[update]
+fn cancel_merge() -> VectuneResult<()> {
+    assert_uploader()?;
+
+    merge::cancel();
+    Ok(())
+}
+
+/// `insert` and `merge` link nodes with `delta::robust_prune`, which compares distances by their
+/// ratio and so needs them to be non-negative, unlike the negated inner products of
+/// `Metric::InnerProduct`.
+fn check_prunable_metric(metadata: &RunningMetadata) -> VectuneResult<()> {
+    match metadata.metric {
+        Metric::Euclidean | Metric::Cosine => Ok(()),
+        metric @ Metric::InnerProduct => Err(VectuneError::MetricNotSupported { metric }),
+    }
+}
+
+fn check_node_indices(node_indices: &[u32], metadata: &RunningMetadata) -> VectuneResult<()> {
+    if let Some(node_index) = node_indices.iter().find(|&&node_index| node_index as u64 >= metadata.num_vectors) {
+        return Err(VectuneError::InvalidArgument(format!(
+            "node index {node_index} is not one of the {} nodes of the graph",
+            metadata.num_vectors
+        )));
+    }
+    Ok(())
+}
+
 #[query]
-fn search_with_simd(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> Vec<(f32, u32)> {
+fn search(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<(f32, u32)>> {
+    access::check_search_access()?;
+    check_search(&query_vector, top_k, size_l)?;
 
-    // ic_cdk::println!("{}\n{}", usize::MAX, u64::MAX);
+    search_running_index(query_vector, top_k, size_l)
+}
 
-    // ic_cdk::println!("stable_size u32: {}", ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE);
+/// Same as `search` as an update call, which counts against the search quota of the caller.
+#[update]
+fn search_update(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<(f32, u32)>> {
+    check_search(&query_vector, top_k, size_l)?;
+    access::count_searches(1)?;
 
-    assert!(top_k <= size_l);
+    search_running_index(query_vector, top_k, size_l)
+}
 
+/// Runs a search passed by `check_search`.
+fn search_running_index(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<(f32, u32)>> {
     METADATA.with(|metadata| {
         let metadata = metadata.borrow();
-        let Metadata::Running(metadata) = &*metadata.get() else {
-            trap("Metadata is not Running")
+        let metadata = metadata.get().running_metadata()?;
+
+        let k_ann = match metadata.metric {
+            Metric::Euclidean => search_graph(metadata, &Point::from_f32_vec(query_vector), top_k, size_l),
+            // `ssd_vectune::point::Point` only knows the Euclidean distance.
+            Metric::Cosine | Metric::InnerProduct => {
+                search_graph(metadata, &simd_query_point(metadata, query_vector), top_k, size_l)
+            }
         };
 
-        let storage_mem = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)));
-        let storage = Storage {
-            storage_mem,
-            sector_byte_size: metadata.sector_byte_size as usize,
+        Ok(k_ann)
+    })
+}
+
+#[query]
+fn search_with_simd(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<(f32, u32)>> {
+    access::check_search_access()?;
+    check_search(&query_vector, top_k, size_l)?;
+
+    search_running_index_with_simd(query_vector, top_k, size_l)
+}
+
+/// Same as `search_with_simd` as an update call, which counts against the search quota of the caller.
+#[update]
+fn search_with_simd_update(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<(f32, u32)>> {
+    check_search(&query_vector, top_k, size_l)?;
+    access::count_searches(1)?;
+
+    search_running_index_with_simd(query_vector, top_k, size_l)
+}
+
+fn search_running_index_with_simd(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<(f32, u32)>> {
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let metadata = metadata.get().running_metadata()?;
+
+        Ok(search_graph(metadata, &simd_query_point(metadata, query_vector), top_k, size_l))
+    })
+}
+
+fn simd_query_point(metadata: &RunningMetadata, query_vector: Vec<f32>) -> SIMDPoint {
+    set_simd_point_params(metadata);
+    SIMDPoint::from_f32_vec(query_vector)
+}
+
+fn set_simd_point_params(metadata: &RunningMetadata) {
+    SIMDPoint::set_dim(metadata.vector_dim as u32);
+    SIMDPoint::set_metric(metadata.metric);
+}
+
+fn search_graph<P>(metadata: &RunningMetadata, query_point: &P, top_k: u64, size_l: u64) -> Vec<(f32, u32)>
+where
+    P: PointInterface,
+    UnorderedGraph<Storage>: GraphInterface<P>,
+{
+    let mut graph = open_graph(metadata, size_l);
+    let tombstones = metadata.slot.tombstones();
+
+    let (k_ann, visited) = vectune::search(&mut graph, query_point, num_candidates(tombstones.as_ref(), top_k, size_l));
+
+    ic_cdk::println!("visited len: {}", visited.len());
+
+    let k_ann = remove_deleted(k_ann, tombstones.as_ref(), top_k);
+    merge_delta(metadata, query_point, k_ann, top_k, size_l)
+}
+
+/// Adds the closest nodes of the delta index to `k_ann`, under the node indices `insert` gave them.
+fn merge_delta<P: PointInterface>(
+    metadata: &RunningMetadata,
+    query_point: &P,
+    mut k_ann: Vec<(f32, u32)>,
+    top_k: u64,
+    size_l: u64,
+) -> Vec<(f32, u32)> {
+    let delta = metadata.slot.delta();
+    if delta.is_empty() {
+        return k_ann;
+    }
+
+    let delta_k_ann = delta::search(&delta, query_point, top_k as usize, size_l as usize);
+    k_ann.extend(delta_k_ann.into_iter().map(|(distance, delta_index)| (distance, metadata.num_vectors as u32 + delta_index)));
+    k_ann.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+    // While a merge runs, a node can be found both in the graph and in the delta index.
+    k_ann.dedup_by_key(|(_, node_index)| *node_index);
+    k_ann.truncate(top_k as usize);
+    k_ann
+}
+
+/// Nodes past the end of `tombstones` are the ones a merge is adding, which are never deleted.
+fn is_deleted(tombstones: &StableBitmap, node_index: u32) -> bool {
+    (node_index as u64) < tombstones.num_bits() && tombstones.get(node_index as u64)
+}
+
+/// How many of the closest nodes to take from `vectune::search`. With deleted nodes, the whole
+/// candidate list is taken, so that `top_k` are left once they are removed.
+fn num_candidates(tombstones: Option<&StableBitmap>, top_k: u64, size_l: u64) -> usize {
+    match tombstones {
+        Some(tombstones) if tombstones.count_ones() > 0 => size_l as usize,
+        _ => top_k as usize,
+    }
+}
+
+fn remove_deleted(mut k_ann: Vec<(f32, u32)>, tombstones: Option<&StableBitmap>, top_k: u64) -> Vec<(f32, u32)> {
+    if let Some(tombstones) = tombstones {
+        k_ann.retain(|&(_, node_index)| !is_deleted(tombstones, node_index));
+    }
+    k_ann.truncate(top_k as usize);
+    k_ann
+}
+
+/// Searches several queries against one graph setup, the same way as `search`.
+///
+/// Stops before a query that would not fit into the instruction limit of a query call, so the
+/// result can hold fewer lists than `queries`. The caller sends the remaining queries again.
+#[query]
+fn search_batch(queries: Vec<Vec<f32>>, top_k: u64, size_l: u64) -> VectuneResult<Vec<Vec<(f32, u32)>>> {
+    access::check_search_access()?;
+    check_search_batch(&queries, top_k, size_l)?;
+
+    search_running_index_batch(queries, top_k, size_l, false)
+}
+
+/// Same as `search_batch` as an update call, which counts every query against the search quota
+/// of the caller, including those left unanswered.
+#[update]
+fn search_batch_update(queries: Vec<Vec<f32>>, top_k: u64, size_l: u64) -> VectuneResult<Vec<Vec<(f32, u32)>>> {
+    check_search_batch(&queries, top_k, size_l)?;
+    access::count_searches(queries.len() as u64)?;
+
+    search_running_index_batch(queries, top_k, size_l, false)
+}
+
+/// Same as `search_batch`, but searches every query the same way as `search_with_simd`.
+#[query]
+fn search_batch_with_simd(queries: Vec<Vec<f32>>, top_k: u64, size_l: u64) -> VectuneResult<Vec<Vec<(f32, u32)>>> {
+    access::check_search_access()?;
+    check_search_batch(&queries, top_k, size_l)?;
+
+    search_running_index_batch(queries, top_k, size_l, true)
+}
+
+/// Same as `search_batch_with_simd` as an update call, see `search_batch_update`.
+#[update]
+fn search_batch_with_simd_update(queries: Vec<Vec<f32>>, top_k: u64, size_l: u64) -> VectuneResult<Vec<Vec<(f32, u32)>>> {
+    check_search_batch(&queries, top_k, size_l)?;
+    access::count_searches(queries.len() as u64)?;
+
+    search_running_index_batch(queries, top_k, size_l, true)
+}
+
+fn search_running_index_batch(
+    queries: Vec<Vec<f32>>,
+    top_k: u64,
+    size_l: u64,
+    with_simd: bool,
+) -> VectuneResult<Vec<Vec<(f32, u32)>>> {
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let metadata = metadata.get().running_metadata()?;
+
+        let k_anns = match metadata.metric {
+            Metric::Euclidean if !with_simd => {
+                search_graph_batch(metadata, queries.into_iter().map(Point::from_f32_vec), top_k, size_l)
+            }
+            _ => {
+                let query_points = queries.into_iter().map(|query_vector| simd_query_point(metadata, query_vector));
+                search_graph_batch(metadata, query_points, top_k, size_l)
+            }
         };
 
-        let unordered_graph_on_storage = GraphStore::new(
-            metadata.num_vectors as usize,
-            metadata.vector_dim as usize,
-            metadata.edge_degrees as usize,
-            storage,
-        );
+        Ok(k_anns)
+    })
+}
+
+fn search_graph_batch<P>(
+    metadata: &RunningMetadata,
+    query_points: impl Iterator<Item = P>,
+    top_k: u64,
+    size_l: u64,
+) -> Vec<Vec<(f32, u32)>>
+where
+    P: PointInterface,
+    UnorderedGraph<Storage>: GraphInterface<P>,
+{
+    let mut graph = open_graph(metadata, size_l);
+    let tombstones = metadata.slot.tombstones();
+    let num_candidates = num_candidates(tombstones.as_ref(), top_k, size_l);
+
+    let mut k_anns = vec![];
+    let mut max_query_instructions = 0;
+    for query_point in query_points {
+        let before = ic_cdk::api::performance_counter(0);
+        if before + max_query_instructions > SEARCH_BATCH_INSTRUCTION_LIMIT {
+            break;
+        }
+
+        let (k_ann, _visited) = vectune::search(&mut graph, &query_point, num_candidates);
+        let k_ann = remove_deleted(k_ann, tombstones.as_ref(), top_k);
+        k_anns.push(merge_delta(metadata, &query_point, k_ann, top_k, size_l));
+
+        max_query_instructions = max_query_instructions.max(ic_cdk::api::performance_counter(0) - before);
+    }
 
-        let mut graph = UnorderedGraph::new(unordered_graph_on_storage, metadata.medoid_node_index);
+    k_anns
+}
+
+fn open_graph(metadata: &RunningMetadata, size_l: u64) -> UnorderedGraph<Storage> {
+    let unordered_graph_on_storage = open_graph_store(metadata);
 
-        graph.set_size_l(size_l as usize);
+    let mut graph = UnorderedGraph::new(unordered_graph_on_storage, metadata.medoid_node_index);
 
-        let (k_ann, visited) = vectune::search(&mut graph, &SIMDPoint::from_f32_vec(query_vector), top_k as usize);
+    graph.set_size_l(size_l as usize);
 
-        ic_cdk::println!("visited len: {}", visited.len());
+    graph
+}
 
-        k_ann
+/// The metric the running index was initialized with.
+#[query]
+fn metric() -> VectuneResult<Metric> {
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        Ok(metadata.get().running_metadata()?.metric)
     })
 }
 
-fn is_owner(controllers: &Vec<Principal>) -> bool {
-    let caller = ic_cdk::caller();
-    controllers.contains(&caller)
+/// Same as `search`, but only returns nodes whose attributes match `filter`. The filter is applied
+/// while the graph is traversed, see `filter::filtered_search`.
+#[query]
+fn search_filtered(query_vector: Vec<f32>, top_k: u64, size_l: u64, filter: Filter) -> VectuneResult<Vec<(f32, u32)>> {
+    access::check_search_access()?;
+    check_search(&query_vector, top_k, size_l)?;
+    filter::check_filter(&filter)?;
+
+    search_running_index_filtered(query_vector, top_k, size_l, filter)
+}
+
+/// Same as `search_filtered` as an update call, which counts against the search quota of the caller.
+#[update]
+fn search_filtered_update(query_vector: Vec<f32>, top_k: u64, size_l: u64, filter: Filter) -> VectuneResult<Vec<(f32, u32)>> {
+    check_search(&query_vector, top_k, size_l)?;
+    filter::check_filter(&filter)?;
+    access::count_searches(1)?;
+
+    search_running_index_filtered(query_vector, top_k, size_l, filter)
+}
+
+fn search_running_index_filtered(query_vector: Vec<f32>, top_k: u64, size_l: u64, filter: Filter) -> VectuneResult<Vec<(f32, u32)>> {
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let metadata = metadata.get().running_metadata()?;
+
+        let k_ann = match metadata.metric {
+            Metric::Euclidean => {
+                search_graph_filtered(metadata, &Point::from_f32_vec(query_vector), top_k, size_l, &filter)
+            }
+            Metric::Cosine | Metric::InnerProduct => {
+                search_graph_filtered(metadata, &simd_query_point(metadata, query_vector), top_k, size_l, &filter)
+            }
+        };
+
+        Ok(k_ann)
+    })
+}
+
+fn search_graph_filtered<P>(metadata: &RunningMetadata, query_point: &P, top_k: u64, size_l: u64, filter: &Filter) -> Vec<(f32, u32)>
+where
+    P: PointInterface,
+    UnorderedGraph<Storage>: GraphInterface<P>,
+{
+    let mut graph = open_graph(metadata, size_l);
+
+    let entry_points = filter::entry_points(filter, &metadata.label_entry_points, metadata.medoid_node_index);
+
+    filter::filtered_search(
+        &mut graph,
+        query_point,
+        &entry_points,
+        top_k as usize,
+        size_l as usize,
+        filter,
+        &metadata.slot.attributes(),
+        metadata.slot.tombstones().as_ref(),
+        SEARCH_BATCH_INSTRUCTION_LIMIT,
+    )
+}
+
+/// Same as `search`, but returns the documents uploaded with `upload_documents`.
+/// Nodes without a document are reported with their node index as ID.
+#[query]
+fn search_documents(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<SearchResult>> {
+    access::check_search_access()?;
+    check_search(&query_vector, top_k, size_l)?;
+
+    search_running_index_documents(query_vector, top_k, size_l)
+}
+
+/// Same as `search_documents` as an update call, which counts against the search quota of the caller.
+#[update]
+fn search_documents_update(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<SearchResult>> {
+    check_search(&query_vector, top_k, size_l)?;
+    access::count_searches(1)?;
+
+    search_running_index_documents(query_vector, top_k, size_l)
+}
+
+fn search_running_index_documents(query_vector: Vec<f32>, top_k: u64, size_l: u64) -> VectuneResult<Vec<SearchResult>> {
+    let k_ann = search_running_index(query_vector, top_k, size_l)?;
+
+    METADATA.with(|metadata| {
+        let metadata = metadata.borrow();
+        let metadata = metadata.get().running_metadata()?;
+
+        let documents = metadata.slot.documents();
+
+        let results = k_ann
+            .into_iter()
+            .map(|(distance, node_index)| match documents.get(&node_index) {
+                Some(Document { id, payload }) => SearchResult { id, distance, payload },
+                None => SearchResult {
+                    id: DocumentId::Nat64(node_index as u64),
+                    distance,
+                    payload: None,
+                },
+            })
+            .collect();
+
+        Ok(results)
+    })
 }
 
 #[query]
@@ -380,6 +1814,132 @@ fn greet(name: String) -> String {
     format!("Hello, {}!", name)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `Metadata` of the baseline release, before slots, digests and metrics existed.
+    #[derive(CandidType)]
+    struct BaselineLoadingMetadata {
+        uploaded_chunks: Vec<u8>,
+        chunk_byte_size: u64,
+        medoid_node_index: u32,
+        sector_byte_size: u64,
+        num_vectors: u64,
+        vector_dim: u64,
+        edge_degrees: u64,
+    }
+
+    #[derive(CandidType)]
+    struct BaselineRunningMetadata {
+        medoid_node_index: u32,
+        sector_byte_size: u64,
+        num_vectors: u64,
+        vector_dim: u64,
+        edge_degrees: u64,
+    }
+
+    #[derive(CandidType)]
+    enum BaselineMetadata {
+        None,
+        Loading(BaselineLoadingMetadata),
+        Running(BaselineRunningMetadata),
+    }
+
+    fn baseline_running_metadata() -> BaselineRunningMetadata {
+        BaselineRunningMetadata { medoid_node_index: 42, sector_byte_size: 4096, num_vectors: 1000, vector_dim: 96, edge_degrees: 70 }
+    }
+
+    fn running_metadata() -> RunningMetadata {
+        RunningMetadata {
+            slot: Slot::Green,
+            chunk_byte_size: 1024,
+            index_digest: vec![7; HASH_BYTE_SIZE],
+            created_at: 1_700_000_000_000_000_000,
+            medoid_node_index: 42,
+            sector_byte_size: 4096,
+            num_vectors: 1000,
+            vector_dim: 96,
+            edge_degrees: 70,
+            metric: Metric::Cosine,
+            label_entry_points: vec![],
+        }
+    }
+
+    fn decode(bytes: Vec<u8>) -> Metadata {
+        Metadata::from_bytes(Cow::Owned(bytes))
+    }
+
+    #[test]
+    fn baseline_running_metadata_keeps_serving() {
+        let Metadata::Running(running) = decode(Encode!(&BaselineMetadata::Running(baseline_running_metadata())).unwrap()) else {
+            panic!("a running index has to stay running");
+        };
+
+        assert!(running.slot == Slot::Blue);
+        assert_eq!(running.chunk_byte_size, 0);
+        assert!(running.index_digest.is_empty());
+        assert_eq!(running.created_at, 0);
+        assert_eq!(
+            (running.medoid_node_index, running.sector_byte_size, running.num_vectors, running.vector_dim, running.edge_degrees),
+            (42, 4096, 1000, 96, 70)
+        );
+        assert_eq!(running.metric, Metric::Euclidean);
+        assert!(running.label_entry_points.is_empty());
+    }
+
+    #[test]
+    fn baseline_uploads_start_over() {
+        let loading = BaselineMetadata::Loading(BaselineLoadingMetadata {
+            uploaded_chunks: vec![0xff; 16],
+            chunk_byte_size: 1024,
+            medoid_node_index: 42,
+            sector_byte_size: 4096,
+            num_vectors: 1000,
+            vector_dim: 96,
+            edge_degrees: 70,
+        });
+        assert!(matches!(decode(Encode!(&loading).unwrap()), Metadata::None));
+        assert!(matches!(decode(Encode!(&BaselineMetadata::None).unwrap()), Metadata::None));
+    }
+
+    #[test]
+    fn versioned_metadata_round_trips() {
+        let bytes = Metadata::Running(running_metadata()).to_bytes().into_owned();
+        assert!(bytes.starts_with(METADATA_MAGIC));
+
+        let Metadata::Running(running) = decode(bytes) else {
+            panic!("a running index has to stay running");
+        };
+        assert!(running.slot == Slot::Green);
+        assert_eq!(running.index_digest, vec![7; HASH_BYTE_SIZE]);
+        assert_eq!(running.created_at, 1_700_000_000_000_000_000);
+        assert_eq!(running.metric, Metric::Cosine);
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata version 2 is newer than this wasm")]
+    fn unknown_metadata_version_traps() {
+        let mut bytes = Metadata::Running(running_metadata()).to_bytes().into_owned();
+        bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+        decode(bytes);
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata is truncated")]
+    fn truncated_metadata_header_traps() {
+        decode(METADATA_MAGIC[..].iter().copied().chain([1, 0]).collect());
+    }
+
+    #[test]
+    #[should_panic(expected = "Metadata version 1 does not decode")]
+    fn truncated_metadata_traps() {
+        let mut bytes = Metadata::Running(running_metadata()).to_bytes().into_owned();
+        bytes.truncate(bytes.len() - 4);
+        decode(bytes);
+    }
+}
+
 /* !!Should be end of this file!! */
 // Enable Candid export
 // cargo build --release --target wasm32-unknown-unknown --package instance
@@ -0,0 +1,514 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::time::Duration;
+
+use candid::{CandidType, Decode, Deserialize, Encode};
+use ic_cdk_timers::TimerId;
+use ic_stable_structures::memory_manager::{MemoryId, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{Cell as StableCell, DefaultMemoryImpl, Storable};
+use ssd_vectune::graph::UnorderedGraph;
+use ssd_vectune::graph_store::GraphStore;
+use ssd_vectune::point::Point;
+use vectune::{GraphInterface, PointInterface};
+
+use crate::bitmap::StableBitmap;
+use crate::delta::{self, DeltaNodes};
+use crate::error::VectuneResult;
+use crate::header;
+use crate::simd_point::{Metric, Point as SIMDPoint};
+use crate::{grow_memory, is_deleted, Metadata, RunningMetadata, Slot, StagingMetadata, Storage, MEMORY_MANAGER, METADATA};
+
+// Timers run as update messages, which are limited to 40 billion instructions.
+const STEP_INSTRUCTION_LIMIT: u64 = 10_000_000_000;
+/// Candidate list size of the search for the neighbors of an inserted node.
+const SIZE_L: usize = 100;
+/// Steps are started by an interval timer rather than by the step before, so that a step which
+/// traps, and loses everything it scheduled, is simply run again.
+const STEP_INTERVAL: Duration = Duration::from_secs(1);
+/// A merge whose steps keep trapping, or which can not finish, is given up after making no
+/// progress for this long.
+const MAX_STALL_NANOS: u64 = 30 * 60 * 1_000_000_000;
+
+thread_local! {
+    static STEP_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+
+    static MERGE: RefCell<StableCell<Merge, VirtualMemory<DefaultMemoryImpl>>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+            Merge::Idle
+        ).unwrap()
+    );
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+enum Merge {
+    Idle,
+    Running(MergeProgress),
+}
+
+impl Storable for Merge {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub enum MergePhase {
+    /// Links the neighbors of deleted nodes to the neighbors of those, visiting the nodes of the
+    /// graph in order, so that no edge leads to a deleted node anymore.
+    Deletes { next_node_index: u64 },
+    /// Writes the nodes of the delta index into the graph with their edges among each other, so
+    /// that every node an inserted one can be linked to is written before the next phase. No node
+    /// of the graph leads to them yet.
+    Writes { next_delta_index: u64 },
+    /// Links the written nodes with the graph, in order: each one keeps the closest nodes a search
+    /// for it visits, and is added to their out-edges.
+    Inserts { next_delta_index: u64 },
+}
+
+/// Where a merge is, saved after every step so that it resumes from there, including after an
+/// upgrade.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MergeProgress {
+    slot: Slot,
+    /// `created_at` of the index being merged, so that a merge never touches the next index when
+    /// this one is replaced.
+    index_created_at: u64,
+    started_at: u64,
+    num_deletes: u64,
+    num_inserts: u64,
+    phase: MergePhase,
+    /// When a step last saved its progress, see `MAX_STALL_NANOS`.
+    last_step_at: u64,
+}
+
+pub fn progress() -> Option<MergeProgress> {
+    MERGE.with(|merge| match merge.borrow().get() {
+        Merge::Idle => None,
+        Merge::Running(progress) => Some(progress.clone()),
+    })
+}
+
+pub fn is_running() -> bool {
+    progress().is_some()
+}
+
+fn set(merge: Merge) {
+    MERGE.with(|cell| {
+        let _ = cell.borrow_mut().set(merge);
+    });
+}
+
+/// Starts folding the delta index and the deletes made so far into the graph of the running
+/// index, or resumes the merge in progress.
+///
+/// The deletes are taken as they are now: deleting more nodes during the merge is fine, they are
+/// folded in by the next one. Inserting is not, since the delta index is emptied at the end.
+pub fn start(metadata: &RunningMetadata) -> VectuneResult<()> {
+    if is_running() {
+        start_steps();
+        return Ok(());
+    }
+
+    set(Merge::Running(new_progress(metadata, ic_cdk::api::time())?));
+    start_steps();
+
+    Ok(())
+}
+
+/// Takes the deletes made so far, and makes room in the graph for the nodes of the delta index.
+fn new_progress(metadata: &RunningMetadata, now: u64) -> VectuneResult<MergeProgress> {
+    let slot = metadata.slot;
+    let merged_deletes = match slot.tombstones() {
+        Some(tombstones) => StableBitmap::copy(slot.merged_deletes_memory(), &tombstones)?,
+        None => StableBitmap::new(slot.merged_deletes_memory(), metadata.num_vectors)?,
+    };
+    let num_inserts = slot.delta().len();
+    let byte_size = header::graph_byte_size(
+        metadata.num_vectors + num_inserts,
+        metadata.vector_dim,
+        metadata.edge_degrees,
+        metadata.sector_byte_size,
+    )?;
+    grow_memory(&slot.storage_memory(), byte_size)?;
+
+    Ok(MergeProgress {
+        slot,
+        index_created_at: metadata.created_at,
+        started_at: now,
+        num_deletes: merged_deletes.count_ones(),
+        num_inserts,
+        phase: MergePhase::Deletes { next_node_index: 0 },
+        last_step_at: now,
+    })
+}
+
+/// Timers do not survive an upgrade, so `post_upgrade` picks up the merge in progress here. The
+/// time the canister spent upgrading does not count as a stall.
+pub fn resume() {
+    if let Some(mut progress) = progress() {
+        progress.last_step_at = ic_cdk::api::time();
+        set(Merge::Running(progress));
+        start_steps();
+    }
+}
+
+/// Stops the merge in progress. What it did so far stays in the graph: nodes it unlinked from
+/// deleted ones keep their new edges, and nodes it already linked to inserted ones keep edges to
+/// node indices past `num_vectors`, which searches may follow. The inserted nodes stay in the delta
+/// index, and the next merge writes and links them again under the same node indices.
+pub fn cancel() {
+    set(Merge::Idle);
+    stop_steps();
+}
+
+fn start_steps() {
+    STEP_TIMER.with(|timer| {
+        timer.borrow_mut().get_or_insert_with(|| ic_cdk_timers::set_timer_interval(STEP_INTERVAL, step));
+    });
+}
+
+fn stop_steps() {
+    if let Some(timer) = STEP_TIMER.with(|timer| timer.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+}
+
+fn step() {
+    let Some(mut progress) = progress() else {
+        stop_steps();
+        return;
+    };
+
+    let metadata = METADATA.with(|metadata| metadata.borrow().get().running_metadata().ok().cloned());
+    let Some(metadata) = metadata.filter(|metadata| {
+        metadata.slot == progress.slot && metadata.created_at == progress.index_created_at
+    }) else {
+        ic_cdk::println!("the index being merged was replaced, dropping the merge");
+        cancel();
+        return;
+    };
+
+    let stalled_nanos = ic_cdk::api::time().saturating_sub(progress.last_step_at);
+    if stalled_nanos > MAX_STALL_NANOS {
+        ic_cdk::println!("the merge made no progress for {}s, giving up", stalled_nanos / 1_000_000_000);
+        cancel();
+        return;
+    }
+
+    let done = match metadata.metric {
+        Metric::Euclidean => run::<Point>(&metadata, &mut progress),
+        Metric::Cosine | Metric::InnerProduct => {
+            crate::set_simd_point_params(&metadata);
+            run::<SIMDPoint>(&metadata, &mut progress)
+        }
+    };
+    if !done {
+        progress.last_step_at = ic_cdk::api::time();
+        set(Merge::Running(progress));
+        return;
+    }
+
+    // Not counted as progress, so that a merge which can never finish is given up eventually.
+    if let Err(err) = finish(&metadata, &progress) {
+        ic_cdk::println!("could not finish the merge, retrying: {err:?}");
+        set(Merge::Running(progress));
+    }
+}
+
+/// Advances `progress` until the instruction limit of a step, and returns whether both phases
+/// are done.
+fn run<P>(metadata: &RunningMetadata, progress: &mut MergeProgress) -> bool
+where
+    P: PointInterface,
+    UnorderedGraph<Storage>: GraphInterface<P>,
+{
+    let graph_store = crate::open_graph_store(metadata);
+    let mut graph = crate::open_graph(metadata, SIZE_L as u64);
+    let merged_deletes = metadata.slot.merged_deletes().expect("start creates the merged deletes");
+    let delta = metadata.slot.delta();
+
+    while ic_cdk::api::performance_counter(0) < STEP_INSTRUCTION_LIMIT {
+        if advance::<P>(&graph_store, &mut graph, &merged_deletes, &delta, metadata, progress) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Does the next node of the phase of `progress`, and returns whether all phases are done.
+fn advance<P>(
+    graph_store: &GraphStore<Storage>,
+    graph: &mut UnorderedGraph<Storage>,
+    merged_deletes: &StableBitmap,
+    delta: &DeltaNodes,
+    metadata: &RunningMetadata,
+    progress: &mut MergeProgress,
+) -> bool
+where
+    P: PointInterface,
+    UnorderedGraph<Storage>: GraphInterface<P>,
+{
+    progress.phase = match progress.phase {
+        MergePhase::Deletes { next_node_index } if progress.num_deletes > 0 && next_node_index < metadata.num_vectors => {
+            unlink_deletes::<P>(graph_store, merged_deletes, next_node_index as u32, metadata.edge_degrees as usize);
+            MergePhase::Deletes { next_node_index: next_node_index + 1 }
+        }
+        MergePhase::Deletes { .. } => MergePhase::Writes { next_delta_index: 0 },
+        MergePhase::Writes { next_delta_index } if next_delta_index < progress.num_inserts => {
+            write_node(graph_store, delta, metadata, next_delta_index as u32);
+            MergePhase::Writes { next_delta_index: next_delta_index + 1 }
+        }
+        MergePhase::Writes { .. } => MergePhase::Inserts { next_delta_index: 0 },
+        MergePhase::Inserts { next_delta_index } if next_delta_index < progress.num_inserts => {
+            link_node::<P>(graph, graph_store, merged_deletes, metadata, next_delta_index as u32);
+            MergePhase::Inserts { next_delta_index: next_delta_index + 1 }
+        }
+        MergePhase::Inserts { .. } => return true,
+    };
+
+    false
+}
+
+/// Replaces the edges of `node_index` to deleted nodes with the best of their own out-edges, as
+/// in the delete phase of FreshDiskANN's StreamingMerge.
+fn unlink_deletes<P: PointInterface>(graph_store: &GraphStore<Storage>, merged_deletes: &StableBitmap, node_index: u32, edge_degrees: usize) {
+    if is_deleted(merged_deletes, node_index) {
+        return;
+    }
+    // Out-edges may lead past `merged_deletes`, to nodes linked by a merge that was cancelled.
+    let (vector, out_edges) = graph_store.read_node(&node_index);
+    if !out_edges.iter().any(|&out_edge| is_deleted(merged_deletes, out_edge)) {
+        return;
+    }
+
+    let mut candidates: Vec<u32> = vec![];
+    for out_edge in out_edges {
+        if is_deleted(merged_deletes, out_edge) {
+            let (_, second_hops) = graph_store.read_node(&out_edge);
+            candidates.extend(second_hops.into_iter().filter(|&second_hop| !is_deleted(merged_deletes, second_hop)));
+        } else {
+            candidates.push(out_edge);
+        }
+    }
+
+    let load_point = |node_index: u32| P::from_f32_vec(graph_store.read_node(&node_index).0);
+    let point = P::from_f32_vec(vector.clone());
+    let candidates = candidates
+        .into_iter()
+        .map(|candidate| (point.distance(&load_point(candidate)), candidate))
+        .collect();
+    let out_edges = delta::robust_prune(candidates, node_index, edge_degrees, load_point);
+
+    graph_store.write_node(&node_index, &vector, &out_edges);
+}
+
+/// Writes the node `delta_index` of the delta index into the graph, under the node index `insert`
+/// gave it, with its out-edges in the delta index.
+fn write_node(graph_store: &GraphStore<Storage>, delta: &DeltaNodes, metadata: &RunningMetadata, delta_index: u32) {
+    let first_inserted = metadata.num_vectors as u32;
+    let delta_node = delta.get(&delta_index).unwrap();
+    // A graph of fewer edges than the delta index keeps the first ones, which the next phase
+    // prunes again together with the nodes of the graph.
+    let out_edges: Vec<u32> = delta_node
+        .out_edges
+        .iter()
+        .take(metadata.edge_degrees as usize)
+        .map(|&out_edge| first_inserted + out_edge)
+        .collect();
+
+    graph_store.write_node(&(first_inserted + delta_index), &delta_node.vector, &out_edges);
+}
+
+/// Gives the written node `delta_index` the best of the nodes a search for it visits and its
+/// edges among the inserted nodes as out-edges, and adds it to the out-edges of those neighbors.
+fn link_node<P>(
+    graph: &mut UnorderedGraph<Storage>,
+    graph_store: &GraphStore<Storage>,
+    merged_deletes: &StableBitmap,
+    metadata: &RunningMetadata,
+    delta_index: u32,
+) where
+    P: PointInterface,
+    UnorderedGraph<Storage>: GraphInterface<P>,
+{
+    let node_index = metadata.num_vectors as u32 + delta_index;
+    let edge_degrees = metadata.edge_degrees as usize;
+    let load_point = |node_index: u32| P::from_f32_vec(graph_store.read_node(&node_index).0);
+
+    let (vector, inserted_out_edges) = graph_store.read_node(&node_index);
+    let point = P::from_f32_vec(vector.clone());
+    let (_, visited) = vectune::search(graph, &point, SIZE_L);
+
+    let mut candidates: Vec<(f32, u32)> = visited
+        .into_iter()
+        .filter(|&(_, candidate)| !is_deleted(merged_deletes, candidate))
+        .collect();
+    candidates.extend(
+        inserted_out_edges
+            .into_iter()
+            .map(|out_edge| (point.distance(&load_point(out_edge)), out_edge)),
+    );
+    let out_edges = delta::robust_prune(candidates, node_index, edge_degrees, &load_point);
+    graph_store.write_node(&node_index, &vector, &out_edges);
+
+    for neighbor in out_edges {
+        let (neighbor_vector, mut neighbor_out_edges) = graph_store.read_node(&neighbor);
+        if neighbor_out_edges.contains(&node_index) {
+            continue;
+        }
+        neighbor_out_edges.push(node_index);
+        if neighbor_out_edges.len() > edge_degrees {
+            let neighbor_point = P::from_f32_vec(neighbor_vector.clone());
+            let candidates = neighbor_out_edges
+                .iter()
+                .map(|&out_edge| (neighbor_point.distance(&load_point(out_edge)), out_edge))
+                .collect();
+            neighbor_out_edges = delta::robust_prune(candidates, neighbor, edge_degrees, &load_point);
+        }
+        graph_store.write_node(&neighbor, &neighbor_vector, &neighbor_out_edges);
+    }
+}
+
+/// Counts the merged nodes in `num_vectors`, so that they are searched as part of the graph, and
+/// empties the delta index.
+fn finish(metadata: &RunningMetadata, progress: &MergeProgress) -> VectuneResult<()> {
+    let slot = metadata.slot;
+    let num_vectors = metadata.num_vectors + progress.num_inserts;
+
+    match slot.tombstones() {
+        Some(tombstones) => tombstones.grow(num_vectors)?,
+        None => {
+            StableBitmap::new(slot.tombstones_memory(), num_vectors)?;
+        }
+    }
+    if let Some(merged_deletes) = slot.merged_deletes() {
+        merged_deletes.grow(num_vectors)?;
+    }
+    slot.clear_delta();
+
+    METADATA.with(|metadata| {
+        let mut metadata = metadata.borrow_mut();
+        let mut current = metadata.get().clone();
+        if let Metadata::Running(running) | Metadata::Staging(StagingMetadata { running, .. }) = &mut current {
+            running.num_vectors = num_vectors;
+        }
+        let _ = metadata.set(current);
+    });
+    set(Merge::Idle);
+    stop_steps();
+
+    ic_cdk::println!(
+        "merged {} deletes and {} inserts in {}s",
+        progress.num_deletes,
+        progress.num_inserts,
+        (ic_cdk::api::time() - progress.started_at) / 1_000_000_000
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::DeltaNode;
+
+    const NUM_VECTORS: u64 = 4;
+
+    fn metadata() -> RunningMetadata {
+        RunningMetadata {
+            slot: Slot::Blue,
+            chunk_byte_size: 0,
+            index_digest: vec![],
+            created_at: 0,
+            medoid_node_index: 0,
+            sector_byte_size: 4096,
+            num_vectors: NUM_VECTORS,
+            vector_dim: 2,
+            edge_degrees: 4,
+            metric: Metric::Euclidean,
+            label_entry_points: vec![],
+        }
+    }
+
+    /// Nodes on a line, each linked to the ones next to it, and two inserts above the middle.
+    fn write_index(metadata: &RunningMetadata) {
+        let byte_size =
+            header::graph_byte_size(metadata.num_vectors, metadata.vector_dim, metadata.edge_degrees, metadata.sector_byte_size).unwrap();
+        grow_memory(&metadata.slot.storage_memory(), byte_size).unwrap();
+        let graph_store = crate::open_graph_store(metadata);
+        for node_index in 0..NUM_VECTORS as u32 {
+            let out_edges: Vec<u32> = [node_index.checked_sub(1), Some(node_index + 1)]
+                .into_iter()
+                .flatten()
+                .filter(|&out_edge| (out_edge as u64) < NUM_VECTORS)
+                .collect();
+            graph_store.write_node(&node_index, &vec![node_index as f32, 0.0], &out_edges);
+        }
+
+        let mut delta = metadata.slot.delta();
+        delta.insert(0, DeltaNode { vector: vec![1.5, 1.0], out_edges: vec![1] });
+        delta.insert(1, DeltaNode { vector: vec![1.5, 2.0], out_edges: vec![0] });
+    }
+
+    /// Runs the phases of `progress` until `stop` holds for the next one, and returns whether the
+    /// merge is done.
+    fn run_until(metadata: &RunningMetadata, progress: &mut MergeProgress, stop: impl Fn(MergePhase) -> bool) -> bool {
+        let graph_store = crate::open_graph_store(metadata);
+        let mut graph = crate::open_graph(metadata, SIZE_L as u64);
+        let merged_deletes = metadata.slot.merged_deletes().unwrap();
+        let delta = metadata.slot.delta();
+        while !stop(progress.phase) {
+            if advance::<Point>(&graph_store, &mut graph, &merged_deletes, &delta, metadata, progress) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn merge_after_a_cancelled_one_skips_edges_past_the_merged_deletes() {
+        let metadata = metadata();
+        write_index(&metadata);
+
+        let mut progress = new_progress(&metadata, 0).unwrap();
+        set(Merge::Running(progress.clone()));
+        let done = run_until(&metadata, &mut progress, |phase| matches!(phase, MergePhase::Inserts { next_delta_index: 1 }));
+        assert!(!done);
+        cancel();
+        assert!(!is_running());
+
+        // The first insert is linked from the graph, under a node index `merged_deletes` does not cover.
+        let graph_store = crate::open_graph_store(&metadata);
+        let first_inserted = NUM_VECTORS as u32;
+        assert!((0..first_inserted).any(|node_index| graph_store.read_node(&node_index).1.contains(&first_inserted)));
+
+        let tombstones = StableBitmap::new(metadata.slot.tombstones_memory(), NUM_VECTORS).unwrap();
+        tombstones.set(2);
+
+        let mut progress = new_progress(&metadata, 0).unwrap();
+        assert_eq!(progress.num_deletes, 1);
+        assert!(run_until(&metadata, &mut progress, |_| false));
+
+        let num_nodes = first_inserted + progress.num_inserts as u32;
+        for node_index in (0..num_nodes).filter(|&node_index| node_index != 2) {
+            let (_, out_edges) = graph_store.read_node(&node_index);
+            assert!(!out_edges.is_empty(), "node {node_index} lost all of its edges");
+            assert!(!out_edges.contains(&2), "node {node_index} still leads to the deleted node");
+        }
+        for inserted in first_inserted..num_nodes {
+            assert!(
+                (0..num_nodes).any(|node_index| node_index != inserted && graph_store.read_node(&node_index).1.contains(&inserted)),
+                "node {inserted} is not linked from any other node"
+            );
+        }
+    }
+}
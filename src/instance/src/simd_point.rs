@@ -1,9 +1,30 @@
 // #[cfg(feature = "simd")]
 // use std::simd::f32x4;
 
+use candid::CandidType;
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use vectune::PointInterface;
 
+thread_local! {
+    // `PointInterface` has no receiver for `dim`, and `distance` has no room for a metric,
+    // so both are taken from the running index and kept here.
+    static DIM: Cell<u32> = const { Cell::new(96) };
+    static METRIC: Cell<Metric> = const { Cell::new(Metric::Euclidean) };
+}
+
+/// How `distance` compares two points. Smaller is always closer, so that the graph search can
+/// stay the same for every metric:
+/// - `Euclidean`: the L2 distance
+/// - `Cosine`: `1 - cosine similarity`
+/// - `InnerProduct`: the negated inner product
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Euclidean,
+    Cosine,
+    InnerProduct,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Point(Vec<f32>);
 impl Point {
@@ -14,72 +35,39 @@ impl Point {
         Point(a.into_iter().collect())
     }
 
-    pub const DIM: usize = 96;
-}
-
-impl PointInterface for Point {
+    /// Sets the dimension returned by `PointInterface::dim` and used by `zero`.
+    /// Has to be called with the `vector_dim` of the index before searching it.
+    pub fn set_dim(dim: u32) {
+        DIM.with(|d| d.set(dim));
+    }
 
-    #[cfg(not(target_arch = "wasm32"))]
-    fn distance(&self, other: &Self) -> f32 {
-        self.0
-            .iter()
-            .zip(other.0.iter())
-            .map(|(a, b)| {
-                let c = a - b;
-                c * c
-            })
-            .sum::<f32>()
-            .sqrt()
+    /// Sets the metric used by `distance`.
+    /// Has to be called with the `metric` of the index before searching it.
+    pub fn set_metric(metric: Metric) {
+        METRIC.with(|m| m.set(metric));
     }
+}
 
-    #[cfg(target_arch = "wasm32")]
-    #[target_feature(enable = "simd128")]
+impl PointInterface for Point {
     fn distance(&self, other: &Self) -> f32 {
-        use core::arch::wasm32::*;
-
-        // ic_cdk::println!("using simd");
-
         assert_eq!(self.0.len(), other.0.len());
 
-        // let mut sum = f32x4::splat(0.0);
-        let mut sum = 0.0_f32;
-        let chunks = self.0.chunks_exact(4).zip(other.0.chunks_exact(4));
-
-        for (a_chunk, b_chunk) in chunks {
-            // let a_simd = f32x4::from_slice(a_chunk);
-            // let b_simd = f32x4::from_slice(b_chunk);
-            let a_simd = unsafe { v128_load(a_chunk.as_ptr() as *const v128) };
-            let b_simd = unsafe { v128_load(b_chunk.as_ptr() as *const v128) };
-            // let diff = a_simd - b_simd;
-            // sum += diff * diff;
-            let diff = f32x4_sub(a_simd, b_simd);
-            let pow2_diff = f32x4_mul(diff, diff);
-
-            let mut res = [0.0; 4];
-            unsafe { v128_store(res.as_mut_ptr() as *mut v128, pow2_diff) };
-            sum += res.into_iter().sum::<f32>();
+        match METRIC.with(|metric| metric.get()) {
+            Metric::Euclidean => squared_euclidean(&self.0, &other.0).sqrt(),
+            Metric::Cosine => {
+                let norms = (dot(&self.0, &self.0) * dot(&other.0, &other.0)).sqrt();
+                if norms == 0.0 {
+                    1.0
+                } else {
+                    1.0 - dot(&self.0, &other.0) / norms
+                }
+            }
+            Metric::InnerProduct => -dot(&self.0, &other.0),
         }
-
-        // Convert SIMD vector sum to an array and sum its elements
-        // let simd_sum: f32 = sum.to_array().iter().sum();
-
-        // Handle remaining elements
-        let remainder_start = self.0.len() - self.0.len() % 4;
-        let remainder_sum: f32 = self.0[remainder_start..]
-            .iter()
-            .zip(&other.0[remainder_start..])
-            .map(|(a, b)| {
-                let diff = a - b;
-                diff * diff
-            })
-            .sum();
-
-        // Calculate the total sum and then the square root
-        (sum + remainder_sum).sqrt()
     }
 
     fn dim() -> u32 {
-        96
+        DIM.with(|dim| dim.get())
     }
 
     fn add(&self, other: &Self) -> Self {
@@ -110,4 +98,78 @@ impl PointInterface for Point {
     fn from_f32_vec(a: Vec<f32>) -> Self {
         Point(a.into_iter().collect())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn squared_euclidean(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a, b)| {
+            let c = a - b;
+            c * c
+        })
+        .sum::<f32>()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(a, b)| a * b).sum::<f32>()
+}
+
+#[cfg(target_arch = "wasm32")]
+#[target_feature(enable = "simd128")]
+fn squared_euclidean(a: &[f32], b: &[f32]) -> f32 {
+    use core::arch::wasm32::*;
+
+    let mut sum = f32x4_splat(0.0);
+    for (a_chunk, b_chunk) in a.chunks_exact(4).zip(b.chunks_exact(4)) {
+        let a_simd = unsafe { v128_load(a_chunk.as_ptr() as *const v128) };
+        let b_simd = unsafe { v128_load(b_chunk.as_ptr() as *const v128) };
+        let diff = f32x4_sub(a_simd, b_simd);
+        sum = f32x4_add(sum, f32x4_mul(diff, diff));
+    }
+
+    // Handle remaining elements
+    let remainder_start = a.len() - a.len() % 4;
+    let remainder_sum: f32 = a[remainder_start..]
+        .iter()
+        .zip(&b[remainder_start..])
+        .map(|(a, b)| {
+            let diff = a - b;
+            diff * diff
+        })
+        .sum();
+
+    sum_lanes(sum) + remainder_sum
+}
+
+#[cfg(target_arch = "wasm32")]
+#[target_feature(enable = "simd128")]
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    use core::arch::wasm32::*;
+
+    let mut sum = f32x4_splat(0.0);
+    for (a_chunk, b_chunk) in a.chunks_exact(4).zip(b.chunks_exact(4)) {
+        let a_simd = unsafe { v128_load(a_chunk.as_ptr() as *const v128) };
+        let b_simd = unsafe { v128_load(b_chunk.as_ptr() as *const v128) };
+        sum = f32x4_add(sum, f32x4_mul(a_simd, b_simd));
+    }
+
+    // Handle remaining elements
+    let remainder_start = a.len() - a.len() % 4;
+    let remainder_sum: f32 = a[remainder_start..]
+        .iter()
+        .zip(&b[remainder_start..])
+        .map(|(a, b)| a * b)
+        .sum();
+
+    sum_lanes(sum) + remainder_sum
+}
+
+#[cfg(target_arch = "wasm32")]
+#[target_feature(enable = "simd128")]
+fn sum_lanes(v: core::arch::wasm32::v128) -> f32 {
+    use core::arch::wasm32::*;
+
+    f32x4_extract_lane::<0>(v) + f32x4_extract_lane::<1>(v) + f32x4_extract_lane::<2>(v) + f32x4_extract_lane::<3>(v)
+}